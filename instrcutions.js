@@ -1,5 +1,5 @@
 const OPTYPE_USIZE = 'usize'
-const OPTYPE_U16 = 'u16'
+const OPTYPE_U32 = 'u32'
 
 const instructions = [
     ['nop'],
@@ -26,8 +26,39 @@ const instructions = [
     ['false'],
     ['anew',OPTYPE_USIZE],
     ['mod'],
-    ['jmp',OPTYPE_U16],
-    ['cjmp',OPTYPE_U16],
+    ['jmp',OPTYPE_U32],
+    ['cjmp',OPTYPE_U32],
+    ['next',OPTYPE_U32],
+    ['not'],
+    ['and',OPTYPE_U32],
+    ['or',OPTYPE_U32],
+    ['capture',OPTYPE_USIZE],
+    ['loadup',OPTYPE_USIZE],
+    ['storeup',OPTYPE_USIZE],
+    ['mnew',OPTYPE_USIZE],
+    ['getfield',OPTYPE_USIZE],
+    ['setfield',OPTYPE_USIZE],
+    ['gload',OPTYPE_USIZE],
+    ['gstore',OPTYPE_USIZE],
+    ['dup',OPTYPE_USIZE],
+    ['str'],
+    ['try',OPTYPE_U32],
+    ['untry',OPTYPE_USIZE],
+    ['throw'],
+    ['shape',OPTYPE_USIZE],
+    ['unpack',OPTYPE_USIZE],
+    ['yield'],
+    ['method',OPTYPE_USIZE],
+    ['band'],
+    ['bor'],
+    ['bxor'],
+    ['shl'],
+    ['shr'],
+    ['bnot'],
+    ['pow'],
+    ['idiv'],
+    ['in'],
+    ['slice'],
 ]
 
 function generate_to_string()
@@ -40,7 +71,7 @@ function generate_to_string()
         if (operand) {
             cases += `(operand)`
         }
-        cases += ` => format!("${name}`
+        cases += ` => write!(f, "${name}`
         if (operand) {
             cases += '({})'
         }
@@ -52,8 +83,8 @@ function generate_to_string()
     }
     cases = cases.slice(0,cases.length-1)
     return `
-impl ToString for Instruction {
-    fn to_string(&self) -> String {
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
 ${cases}
         }
@@ -72,13 +103,16 @@ function generate_encode_params()
             cases += `(operand)`
         }
         cases += ` => (I${name.toUpperCase()}`
-        if (operand) {
+        if (operand == OPTYPE_U32) {
             cases += `, *operand as usize`
         }
+        else if (operand) {
+            cases += `, *operand`
+        }
         else {
             cases += ', 0usize'
         }
-        cases += `, ${operand ? (operand == OPTYPE_U16 ? 'Encoding::Fixed(2)' : 'Encoding::Variadic') : 'Encoding::None'}),\n`
+        cases += `, ${operand ? (operand == OPTYPE_U32 ? 'Encoding::Fixed(4)' : 'Encoding::Variadic') : 'Encoding::None'}),\n`
     }
     cases = cases.slice(0,cases.length-1)
     return `
@@ -105,6 +139,8 @@ function generate_enum()
     }
     cases = cases.slice(0,cases.length-1)
     return `#[repr(u8)]
+#[derive(Clone, Copy)]
+#[allow(dead_code)]
 pub enum Instruction {
 ${cases}
 }`
@@ -113,17 +149,28 @@ ${cases}
 function generate_constants()
 {
     let cases = ''
+    let with_operand = 0
+    let without_operand = 0
     for (let i = 0;i<instructions.length;i++)
     {
         const [name,operand] = instructions[i]
-        cases += `pub const I${name.toUpperCase()}: u8 = ${i | (operand ? 0b00100000 : 0)};\n`
+        let opcode
+        if (operand) {
+            opcode = with_operand | 0b00100000
+            with_operand++
+        }
+        else {
+            opcode = (without_operand & 0b00011111) | ((without_operand >> 5) << 6)
+            without_operand++
+        }
+        cases += `pub const I${name.toUpperCase()}: u8 = ${opcode};\n`
     }
     return cases
 }
 
 function generate_imports()
 {
-    return 'use crate::assemble::Encoding;\n'
+    return 'use crate::assemble::Encoding;\nuse std::fmt;\n'
 }
 
 console.log(generate_imports())
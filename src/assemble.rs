@@ -21,12 +21,14 @@ pub fn encode_with_size(opcode: u8, operand: usize, size: u8) -> Bytecode {
         _ => panic!(),
     };
     let mut operand = operand;
-    let mut bytecode = Bytecode::default();
-    bytecode.len = size + 1;
+    let mut bytecode = Bytecode {
+        len: size + 1,
+        ..Default::default()
+    };
     bytecode.bytes[0] = opcode | (x << 6);
     for i in 1..bytecode.len {
         bytecode.bytes[i as usize] = (operand & 0xff) as u8;
-        operand = operand >> 8;
+        operand >>= 8;
     }
     bytecode
 }
@@ -47,8 +49,10 @@ pub fn encode(instruction: Instruction) -> Bytecode {
             }
         }
         Encoding::None => {
-            let mut bytecode = Bytecode::default();
-            bytecode.len = 1;
+            let mut bytecode = Bytecode {
+                len: 1,
+                ..Default::default()
+            };
             bytecode.bytes[0] = opcode;
             bytecode
         }
@@ -1,4 +1,5 @@
 use crate::assemble::Encoding;
+use std::fmt;
 
 pub const INOP: u8 = 0;
 pub const IADD: u8 = 1;
@@ -13,21 +14,54 @@ pub const IGT: u8 = 9;
 pub const ILT: u8 = 10;
 pub const ISET: u8 = 11;
 pub const IGET: u8 = 12;
-pub const IPOP: u8 = 45;
-pub const IRET: u8 = 14;
-pub const ILOAD: u8 = 47;
-pub const ISTORE: u8 = 48;
-pub const ICALL: u8 = 49;
-pub const IKONST: u8 = 50;
-pub const INIL: u8 = 19;
-pub const ITRUE: u8 = 20;
-pub const IFALSE: u8 = 21;
-pub const IANEW: u8 = 54;
-pub const IMOD: u8 = 23;
-pub const IJMP: u8 = 56;
-pub const ICJMP: u8 = 57;
+pub const IPOP: u8 = 32;
+pub const IRET: u8 = 13;
+pub const ILOAD: u8 = 33;
+pub const ISTORE: u8 = 34;
+pub const ICALL: u8 = 35;
+pub const IKONST: u8 = 36;
+pub const INIL: u8 = 14;
+pub const ITRUE: u8 = 15;
+pub const IFALSE: u8 = 16;
+pub const IANEW: u8 = 37;
+pub const IMOD: u8 = 17;
+pub const IJMP: u8 = 38;
+pub const ICJMP: u8 = 39;
+pub const INEXT: u8 = 40;
+pub const INOT: u8 = 18;
+pub const IAND: u8 = 41;
+pub const IOR: u8 = 42;
+pub const ICAPTURE: u8 = 43;
+pub const ILOADUP: u8 = 44;
+pub const ISTOREUP: u8 = 45;
+pub const IMNEW: u8 = 46;
+pub const IGETFIELD: u8 = 47;
+pub const ISETFIELD: u8 = 48;
+pub const IGLOAD: u8 = 49;
+pub const IGSTORE: u8 = 50;
+pub const IDUP: u8 = 51;
+pub const ISTR: u8 = 19;
+pub const ITRY: u8 = 52;
+pub const IUNTRY: u8 = 53;
+pub const ITHROW: u8 = 20;
+pub const ISHAPE: u8 = 54;
+pub const IUNPACK: u8 = 55;
+pub const IYIELD: u8 = 21;
+pub const IMETHOD: u8 = 56;
+pub const IBAND: u8 = 22;
+pub const IBOR: u8 = 23;
+pub const IBXOR: u8 = 24;
+pub const ISHL: u8 = 25;
+pub const ISHR: u8 = 26;
+pub const IBNOT: u8 = 27;
+pub const IPOW: u8 = 28;
+pub const IIDIV: u8 = 29;
+pub const IIN: u8 = 30;
+pub const ISLICE: u8 = 31;
 
 #[repr(u8)]
+#[derive(Clone, Copy)]
+#[allow(dead_code)]
 pub enum Instruction {
     Nop = INOP,
     Add = IADD,
@@ -53,39 +87,101 @@ pub enum Instruction {
     False = IFALSE,
     Anew(usize) = IANEW,
     Mod = IMOD,
-    Jmp(u16) = IJMP,
-    Cjmp(u16) = ICJMP,
+    Jmp(u32) = IJMP,
+    Cjmp(u32) = ICJMP,
+    Next(u32) = INEXT,
+    Not = INOT,
+    And(u32) = IAND,
+    Or(u32) = IOR,
+    Capture(usize) = ICAPTURE,
+    Loadup(usize) = ILOADUP,
+    Storeup(usize) = ISTOREUP,
+    Mnew(usize) = IMNEW,
+    Getfield(usize) = IGETFIELD,
+    Setfield(usize) = ISETFIELD,
+    Gload(usize) = IGLOAD,
+    Gstore(usize) = IGSTORE,
+    Dup(usize) = IDUP,
+    Str = ISTR,
+    Try(u32) = ITRY,
+    Untry(usize) = IUNTRY,
+    Throw = ITHROW,
+    Shape(usize) = ISHAPE,
+    Unpack(usize) = IUNPACK,
+    Yield = IYIELD,
+    Method(usize) = IMETHOD,
+    Band = IBAND,
+    Bor = IBOR,
+    Bxor = IBXOR,
+    Shl = ISHL,
+    Shr = ISHR,
+    Bnot = IBNOT,
+    Pow = IPOW,
+    Idiv = IIDIV,
+    In = IIN,
+    Slice = ISLICE,
 }
 
-impl ToString for Instruction {
-    fn to_string(&self) -> String {
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Instruction::Nop => format!("nop"),
-            Instruction::Add => format!("add"),
-            Instruction::Sub => format!("sub"),
-            Instruction::Mult => format!("mult"),
-            Instruction::Div => format!("div"),
-            Instruction::Eq => format!("eq"),
-            Instruction::Ne => format!("ne"),
-            Instruction::Ge => format!("ge"),
-            Instruction::Le => format!("le"),
-            Instruction::Gt => format!("gt"),
-            Instruction::Lt => format!("lt"),
-            Instruction::Set => format!("set"),
-            Instruction::Get => format!("get"),
-            Instruction::Pop(operand) => format!("pop({})", operand),
-            Instruction::Ret => format!("ret"),
-            Instruction::Load(operand) => format!("load({})", operand),
-            Instruction::Store(operand) => format!("store({})", operand),
-            Instruction::Call(operand) => format!("call({})", operand),
-            Instruction::Konst(operand) => format!("konst({})", operand),
-            Instruction::Nil => format!("nil"),
-            Instruction::True => format!("true"),
-            Instruction::False => format!("false"),
-            Instruction::Anew(operand) => format!("anew({})", operand),
-            Instruction::Mod => format!("mod"),
-            Instruction::Jmp(operand) => format!("jmp({})", operand),
-            Instruction::Cjmp(operand) => format!("cjmp({})", operand),
+            Instruction::Nop => write!(f, "nop"),
+            Instruction::Add => write!(f, "add"),
+            Instruction::Sub => write!(f, "sub"),
+            Instruction::Mult => write!(f, "mult"),
+            Instruction::Div => write!(f, "div"),
+            Instruction::Eq => write!(f, "eq"),
+            Instruction::Ne => write!(f, "ne"),
+            Instruction::Ge => write!(f, "ge"),
+            Instruction::Le => write!(f, "le"),
+            Instruction::Gt => write!(f, "gt"),
+            Instruction::Lt => write!(f, "lt"),
+            Instruction::Set => write!(f, "set"),
+            Instruction::Get => write!(f, "get"),
+            Instruction::Pop(operand) => write!(f, "pop({})", operand),
+            Instruction::Ret => write!(f, "ret"),
+            Instruction::Load(operand) => write!(f, "load({})", operand),
+            Instruction::Store(operand) => write!(f, "store({})", operand),
+            Instruction::Call(operand) => write!(f, "call({})", operand),
+            Instruction::Konst(operand) => write!(f, "konst({})", operand),
+            Instruction::Nil => write!(f, "nil"),
+            Instruction::True => write!(f, "true"),
+            Instruction::False => write!(f, "false"),
+            Instruction::Anew(operand) => write!(f, "anew({})", operand),
+            Instruction::Mod => write!(f, "mod"),
+            Instruction::Jmp(operand) => write!(f, "jmp({})", operand),
+            Instruction::Cjmp(operand) => write!(f, "cjmp({})", operand),
+            Instruction::Next(operand) => write!(f, "next({})", operand),
+            Instruction::Not => write!(f, "not"),
+            Instruction::And(operand) => write!(f, "and({})", operand),
+            Instruction::Or(operand) => write!(f, "or({})", operand),
+            Instruction::Capture(operand) => write!(f, "capture({})", operand),
+            Instruction::Loadup(operand) => write!(f, "loadup({})", operand),
+            Instruction::Storeup(operand) => write!(f, "storeup({})", operand),
+            Instruction::Mnew(operand) => write!(f, "mnew({})", operand),
+            Instruction::Getfield(operand) => write!(f, "getfield({})", operand),
+            Instruction::Setfield(operand) => write!(f, "setfield({})", operand),
+            Instruction::Gload(operand) => write!(f, "gload({})", operand),
+            Instruction::Gstore(operand) => write!(f, "gstore({})", operand),
+            Instruction::Dup(operand) => write!(f, "dup({})", operand),
+            Instruction::Str => write!(f, "str"),
+            Instruction::Try(operand) => write!(f, "try({})", operand),
+            Instruction::Untry(operand) => write!(f, "untry({})", operand),
+            Instruction::Throw => write!(f, "throw"),
+            Instruction::Shape(operand) => write!(f, "shape({})", operand),
+            Instruction::Unpack(operand) => write!(f, "unpack({})", operand),
+            Instruction::Yield => write!(f, "yield"),
+            Instruction::Method(operand) => write!(f, "method({})", operand),
+            Instruction::Band => write!(f, "band"),
+            Instruction::Bor => write!(f, "bor"),
+            Instruction::Bxor => write!(f, "bxor"),
+            Instruction::Shl => write!(f, "shl"),
+            Instruction::Shr => write!(f, "shr"),
+            Instruction::Bnot => write!(f, "bnot"),
+            Instruction::Pow => write!(f, "pow"),
+            Instruction::Idiv => write!(f, "idiv"),
+            Instruction::In => write!(f, "in"),
+            Instruction::Slice => write!(f, "slice"),
         }
     }
 }
@@ -106,19 +202,50 @@ impl Instruction {
             Instruction::Lt => (ILT, 0usize, Encoding::None),
             Instruction::Set => (ISET, 0usize, Encoding::None),
             Instruction::Get => (IGET, 0usize, Encoding::None),
-            Instruction::Pop(operand) => (IPOP, *operand as usize, Encoding::Variadic),
+            Instruction::Pop(operand) => (IPOP, *operand, Encoding::Variadic),
             Instruction::Ret => (IRET, 0usize, Encoding::None),
-            Instruction::Load(operand) => (ILOAD, *operand as usize, Encoding::Variadic),
-            Instruction::Store(operand) => (ISTORE, *operand as usize, Encoding::Variadic),
-            Instruction::Call(operand) => (ICALL, *operand as usize, Encoding::Variadic),
-            Instruction::Konst(operand) => (IKONST, *operand as usize, Encoding::Variadic),
+            Instruction::Load(operand) => (ILOAD, *operand, Encoding::Variadic),
+            Instruction::Store(operand) => (ISTORE, *operand, Encoding::Variadic),
+            Instruction::Call(operand) => (ICALL, *operand, Encoding::Variadic),
+            Instruction::Konst(operand) => (IKONST, *operand, Encoding::Variadic),
             Instruction::Nil => (INIL, 0usize, Encoding::None),
             Instruction::True => (ITRUE, 0usize, Encoding::None),
             Instruction::False => (IFALSE, 0usize, Encoding::None),
-            Instruction::Anew(operand) => (IANEW, *operand as usize, Encoding::Variadic),
+            Instruction::Anew(operand) => (IANEW, *operand, Encoding::Variadic),
             Instruction::Mod => (IMOD, 0usize, Encoding::None),
-            Instruction::Jmp(operand) => (IJMP, *operand as usize, Encoding::Fixed(2)),
-            Instruction::Cjmp(operand) => (ICJMP, *operand as usize, Encoding::Fixed(2)),
+            Instruction::Jmp(operand) => (IJMP, *operand as usize, Encoding::Fixed(4)),
+            Instruction::Cjmp(operand) => (ICJMP, *operand as usize, Encoding::Fixed(4)),
+            Instruction::Next(operand) => (INEXT, *operand as usize, Encoding::Fixed(4)),
+            Instruction::Not => (INOT, 0usize, Encoding::None),
+            Instruction::And(operand) => (IAND, *operand as usize, Encoding::Fixed(4)),
+            Instruction::Or(operand) => (IOR, *operand as usize, Encoding::Fixed(4)),
+            Instruction::Capture(operand) => (ICAPTURE, *operand, Encoding::Variadic),
+            Instruction::Loadup(operand) => (ILOADUP, *operand, Encoding::Variadic),
+            Instruction::Storeup(operand) => (ISTOREUP, *operand, Encoding::Variadic),
+            Instruction::Mnew(operand) => (IMNEW, *operand, Encoding::Variadic),
+            Instruction::Getfield(operand) => (IGETFIELD, *operand, Encoding::Variadic),
+            Instruction::Setfield(operand) => (ISETFIELD, *operand, Encoding::Variadic),
+            Instruction::Gload(operand) => (IGLOAD, *operand, Encoding::Variadic),
+            Instruction::Gstore(operand) => (IGSTORE, *operand, Encoding::Variadic),
+            Instruction::Dup(operand) => (IDUP, *operand, Encoding::Variadic),
+            Instruction::Str => (ISTR, 0usize, Encoding::None),
+            Instruction::Try(operand) => (ITRY, *operand as usize, Encoding::Fixed(4)),
+            Instruction::Untry(operand) => (IUNTRY, *operand, Encoding::Variadic),
+            Instruction::Throw => (ITHROW, 0usize, Encoding::None),
+            Instruction::Shape(operand) => (ISHAPE, *operand, Encoding::Variadic),
+            Instruction::Unpack(operand) => (IUNPACK, *operand, Encoding::Variadic),
+            Instruction::Yield => (IYIELD, 0usize, Encoding::None),
+            Instruction::Method(operand) => (IMETHOD, *operand, Encoding::Variadic),
+            Instruction::Band => (IBAND, 0usize, Encoding::None),
+            Instruction::Bor => (IBOR, 0usize, Encoding::None),
+            Instruction::Bxor => (IBXOR, 0usize, Encoding::None),
+            Instruction::Shl => (ISHL, 0usize, Encoding::None),
+            Instruction::Shr => (ISHR, 0usize, Encoding::None),
+            Instruction::Bnot => (IBNOT, 0usize, Encoding::None),
+            Instruction::Pow => (IPOW, 0usize, Encoding::None),
+            Instruction::Idiv => (IIDIV, 0usize, Encoding::None),
+            Instruction::In => (IIN, 0usize, Encoding::None),
+            Instruction::Slice => (ISLICE, 0usize, Encoding::None),
         }
     }
 }
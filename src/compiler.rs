@@ -1,4 +1,7 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::Arc;
 type Scope = HashMap<String, usize>;
 use crate::assemble::encode;
 use crate::bin::Instruction;
@@ -17,6 +20,61 @@ pub(crate) struct Compiler<V: VM> {
     token_buffer: Option<Token>,
     scopes: Vec<Scope>,
     offset: usize,
+    loops: Vec<Loop>,
+    tries: usize,
+    temps: usize,
+    functions: Vec<FnScope>,
+    fields: HashMap<String, usize>,
+    prelude: Scope,
+    statics: Scope,
+    declared: HashSet<String>,
+    forwards: Scope,
+    imports: HashMap<String, Rc<Module>>,
+    modules: HashMap<PathBuf, Rc<Module>>,
+    loading: Vec<PathBuf>,
+    paths: Vec<PathBuf>,
+}
+
+struct FnScope {
+    depth: usize,
+    upvalues: Vec<(bool, usize)>,
+    generator: Option<Token>,
+}
+
+struct Module {
+    consts: Scope,
+    statics: Scope,
+}
+
+enum Variable {
+    Local(usize),
+    Upvalue(usize),
+    Static(usize),
+    Global(usize),
+    Forward(usize),
+    Module(Rc<Module>),
+}
+
+#[derive(Default)]
+struct Params {
+    count: usize,
+    required: usize,
+    rest: bool,
+    defaults: Vec<usize>,
+}
+
+struct Loop {
+    start: usize,
+    depth: usize,
+    tries: usize,
+    breaks: Vec<usize>,
+}
+
+enum Pattern {
+    Wildcard,
+    Binding(Token),
+    Literal(Token, bool),
+    Array(Vec<Pattern>),
 }
 
 pub(crate) type CResult<T> = Result<T, Error>;
@@ -29,33 +87,62 @@ impl<V: VM> Compiler<V> {
         Error::Immutable(token)
     }
     fn pwr_infix(&self, op: &str) -> Option<(u32, u32)> {
-        if op == "+" || op == "-" {
+        if op == "?" {
+            Some((44, 43))
+        } else if op == "or" || op == "||" {
+            Some((45, 46))
+        } else if op == "and" || op == "&&" {
+            Some((47, 48))
+        } else if op == "|" {
             Some((51, 52))
-        } else if op == "*" || op == "/" || op == "%" {
+        } else if op == "^" {
             Some((53, 54))
-        } else if op == "<" || op == ">" || op == "<=" || op == ">=" || op == "==" || op == "!=" {
+        } else if op == "&" {
+            Some((55, 56))
+        } else if op == "<<" || op == ">>" {
+            Some((57, 58))
+        } else if op == "+" || op == "-" {
+            Some((59, 60))
+        } else if op == "*" || op == "/" || op == "%" {
+            Some((61, 62))
+        } else if op == "**" {
+            Some((66, 65))
+        } else if op == "//" {
+            Some((61, 62))
+        } else if op == "<"
+            || op == ">"
+            || op == "<="
+            || op == ">="
+            || op == "=="
+            || op == "!="
+            || op == "in"
+        {
             Some((49, 50))
         } else {
             None
         }
     }
     fn pwr_postfix(&self, op: &str) -> Option<(u32, ())> {
-        if op == "(" || op == "[" {
-            Some((59, ()))
+        if op == "(" || op == "[" || op == "." {
+            Some((67, ()))
         } else {
             None
         }
     }
     fn pwr_prefix(&self, op: &str) -> Option<((), u32)> {
-        if op == "+" || op == "-" {
-            Some(((), 56))
+        if op == "+" || op == "-" || op == "!" || op == "~" {
+            Some(((), 64))
+        } else if op == "not" {
+            Some(((), 49))
         } else {
             None
         }
     }
     fn token(&mut self) -> CResult<Token> {
         let t = self.scanner.next();
-        if t.is_error() {
+        if t.kind == TokenKind::Unterminated {
+            Err(Error::UnterminatedString(t))
+        } else if t.is_error() {
             Err(Error::Scanner)
         } else {
             Ok(t)
@@ -78,11 +165,26 @@ impl<V: VM> Compiler<V> {
             Ok(t)
         }
     }
-    fn emit(&mut self, instruction: Instruction) {
+    fn emit(&mut self, instruction: Instruction) -> usize {
+        let address = self.vm.address();
         let bytecode = encode(instruction);
         for i in 0..bytecode.len {
             self.vm.emit(bytecode.bytes[i as usize]);
         }
+        address
+    }
+    fn target(&self, address: usize) -> CResult<u32> {
+        u32::try_from(address).map_err(|_| Error::ProgramTooLarge)
+    }
+    fn here(&self) -> CResult<u32> {
+        self.target(self.vm.address())
+    }
+    fn patch(&mut self, address: usize, instruction: Instruction) {
+        let bytecode = encode(instruction);
+        for i in 0..bytecode.len {
+            self.vm
+                .edit(bytecode.bytes[i as usize], address + i as usize);
+        }
     }
     fn expect(&mut self, kind: TokenKind) -> CResult<Token> {
         let token = self.pop()?;
@@ -98,32 +200,146 @@ impl<V: VM> Compiler<V> {
             TokenKind::Single('*') => Instruction::Mult,
             TokenKind::Single('/') => Instruction::Div,
             TokenKind::Single('%') => Instruction::Mod,
+            TokenKind::Single('<') => Instruction::Lt,
+            TokenKind::Single('>') => Instruction::Gt,
+            TokenKind::Single('&') => Instruction::Band,
+            TokenKind::Single('|') => Instruction::Bor,
+            TokenKind::Single('^') => Instruction::Bxor,
+            TokenKind::In => Instruction::In,
             TokenKind::Double => match token.text(self.text.clone()).as_str() {
+                "**" => Instruction::Pow,
+                "//" => Instruction::Idiv,
+                "<<" => Instruction::Shl,
+                ">>" => Instruction::Shr,
                 "==" => Instruction::Eq,
                 "!=" => Instruction::Ne,
                 ">=" => Instruction::Ge,
                 "<=" => Instruction::Le,
-                "<" => Instruction::Lt,
-                ">" => Instruction::Gt,
                 _ => panic!("IMPOSSIBLE!"),
             },
             _ => panic!("IMPOSSIBLE!"),
         }
     }
+    fn compile_assign_operator(&self, token: Token) -> Option<Instruction> {
+        if token.kind != TokenKind::Double {
+            return None;
+        }
+        match self.get_token_text(token).as_str() {
+            "+=" => Some(Instruction::Add),
+            "-=" => Some(Instruction::Sub),
+            "*=" => Some(Instruction::Mult),
+            "/=" => Some(Instruction::Div),
+            "%=" => Some(Instruction::Mod),
+            _ => None,
+        }
+    }
+    fn compile_literal(&self, token: Token) -> CResult<String> {
+        let chars = &self.text[token.from..token.from + token.len];
+        let triple = |c: &[char]| c.len() >= 3 && c[0] == c[1] && c[1] == c[2];
+        let start = if chars[0] == '}' || !triple(chars) {
+            1
+        } else {
+            3
+        };
+        let end =
+            if chars[chars.len() - 1] == '{' || !triple(&chars[chars.len().saturating_sub(3)..]) {
+                1
+            } else {
+                3
+            };
+        let mut literal = String::new();
+        let mut i = start;
+        while i < chars.len() - end {
+            let c = chars[i];
+            i += 1;
+            if c != '\\' {
+                literal.push(c);
+                continue;
+            }
+            let start = i - 1;
+            let invalid = |len: usize| {
+                Error::InvalidEscape(Token {
+                    from: token.from + start,
+                    len,
+                    kind: TokenKind::Literal,
+                })
+            };
+            let e = chars[i];
+            i += 1;
+            match e {
+                'n' => literal.push('\n'),
+                't' => literal.push('\t'),
+                'r' => literal.push('\r'),
+                '0' => literal.push('\0'),
+                '\\' => literal.push('\\'),
+                '\'' => literal.push('\''),
+                '"' => literal.push('"'),
+                '{' => literal.push('{'),
+                '}' => literal.push('}'),
+                'u' => {
+                    if chars[i] != '{' {
+                        return Err(invalid(2));
+                    }
+                    let end = match chars[i..].iter().position(|c| *c == '}') {
+                        Some(end) => i + end,
+                        None => return Err(invalid(2)),
+                    };
+                    let code = String::from_iter(&chars[i + 1..end]);
+                    match u32::from_str_radix(&code, 16).ok().and_then(char::from_u32) {
+                        Some(c) if !code.is_empty() && code.len() <= 6 => literal.push(c),
+                        _ => return Err(invalid(end + 1 - start)),
+                    }
+                    i = end + 1;
+                }
+                _ => return Err(invalid(2)),
+            }
+        }
+        Ok(literal)
+    }
+    fn compile_number(&mut self, token: Token) -> CResult<usize> {
+        let text = token.text(self.text.clone());
+        let (radix, digits) = match text.get(..2) {
+            Some("0x") | Some("0X") => (16, &text[2..]),
+            Some("0b") | Some("0B") => (2, &text[2..]),
+            Some("0o") | Some("0O") => (8, &text[2..]),
+            _ => (10, text.as_str()),
+        };
+        let chars = digits.chars().collect::<Vec<_>>();
+        let separated = chars.iter().enumerate().all(|(i, c)| {
+            *c != '_'
+                || (i > 0
+                    && i + 1 < chars.len()
+                    && chars[i - 1].is_digit(radix)
+                    && chars[i + 1].is_digit(radix))
+        });
+        let digits = digits.replace('_', "");
+        if !separated || digits.is_empty() {
+            Err(Error::InvalidNumber(token))
+        } else if radix == 10 && digits.contains(['.', 'e', 'E']) {
+            match digits.parse() {
+                Ok(number) => Ok(self.vm.rodata_number(number)),
+                Err(_) => Err(Error::InvalidNumber(token)),
+            }
+        } else if radix == 10 {
+            match digits.parse() {
+                Ok(number) => Ok(self.vm.rodata_int(number)),
+                Err(_) => Err(Error::InvalidNumber(token)),
+            }
+        } else if digits.chars().all(|c| c.is_digit(radix)) {
+            match u64::from_str_radix(&digits, radix) {
+                Ok(number) => Ok(self.vm.rodata_int(number as i64)),
+                Err(_) => Err(Error::InvalidNumber(token)),
+            }
+        } else {
+            Err(Error::InvalidNumber(token))
+        }
+    }
     fn compile_atom(&mut self, token: Token) -> CResult<Instruction> {
         match token.kind {
-            TokenKind::Number => Ok(Instruction::Konst(
-                self.vm.rodata_number(
-                    token
-                        .text(self.text.clone())
-                        .parse()
-                        .expect("INVALID NUMERIC CONSTANT"),
-                ),
-            )),
+            TokenKind::Number => Ok(Instruction::Konst(self.compile_number(token)?)),
             TokenKind::Literal => Ok({
-                let name = token.text(self.text.clone());
-                let name = name[1..name.len() - 1].to_string();
-                Instruction::Konst(self.vm.rodata_literal(name))
+                let literal = self.compile_literal(token)?;
+                Instruction::Konst(self.vm.rodata_literal(literal))
             }),
             TokenKind::True => Ok(Instruction::True),
             TokenKind::False => Ok(Instruction::False),
@@ -140,9 +356,13 @@ impl<V: VM> Compiler<V> {
         if let Some((_, rp)) = self.pwr_prefix(token.text(self.text.clone()).as_str()) {
             self.expr_p(rp)?;
             if token.kind == TokenKind::Single('-') {
-                let address = self.vm.rodata_number(-1.0);
+                let address = self.vm.rodata_int(-1);
                 self.emit(Instruction::Konst(address));
                 self.emit(Instruction::Mult);
+            } else if token.kind == TokenKind::Single('!') || token.kind == TokenKind::Not {
+                self.emit(Instruction::Not);
+            } else if token.kind == TokenKind::Single('~') {
+                self.emit(Instruction::Bnot);
             }
         } else if token.text(self.text.clone()).as_str() == "(" {
             self.expr()?;
@@ -150,27 +370,52 @@ impl<V: VM> Compiler<V> {
         } else if token.text(self.text.clone()).as_str() == "[" {
             let count = self.explist(']')?;
             self.emit(Instruction::Anew(count));
+        } else if token.is('{') {
+            let count = self.entrylist()?;
+            self.emit(Instruction::Mnew(count));
+        } else if token.kind == TokenKind::Fn {
+            self.closure()?;
+        } else if token.kind == TokenKind::Interpolation {
+            self.interpolation(token)?;
+        } else if token.kind == TokenKind::Match {
+            self.match_expr(token, false)?;
+        } else if token.kind == TokenKind::If {
+            self.if_expr()?;
+        } else if token.kind == TokenKind::Yield {
+            match self.functions.last_mut() {
+                Some(function) => function.generator = Some(token),
+                None => return Err(self.error_unexpected(token)),
+            }
+            self.expr()?;
+            self.emit(Instruction::Yield);
         } else {
             let i = self.compile_atom(token)?;
             self.emit(i);
         }
 
+        let postfix = token.kind == TokenKind::Identifier || token.is('(') || token.is('[');
+        self.operators(pwr, postfix)
+    }
+    fn operators(&mut self, pwr: u32, mut postfix: bool) -> CResult<()> {
         loop {
             let t = self.peek()?;
             match t.kind {
                 TokenKind::Single(c) => {
-                    if c == '}' || c == '{' || c == ',' || c == ')' || c == ']' {
+                    if c == '}' || c == '{' || c == ',' || c == ')' || c == ']' || c == ':' {
+                        break;
+                    }
+                }
+                TokenKind::Double => {
+                    if t.text(self.text.clone()).as_str() == ".." {
                         break;
-                    } else {
-                        ()
                     }
                 }
-                TokenKind::Double => (),
+                TokenKind::And | TokenKind::Or | TokenKind::In => (),
                 _ => break,
             }
             let ttext = t.text(self.text.clone());
             if let Some((lp, _)) = self.pwr_postfix(ttext.as_str()) {
-                if token.kind != TokenKind::Identifier && !token.is('(') && !token.is('[') {
+                if !t.is('.') && !postfix {
                     break;
                 }
                 if pwr > lp {
@@ -178,11 +423,15 @@ impl<V: VM> Compiler<V> {
                 }
                 self.pop()?;
                 if t.kind == TokenKind::Single('(') {
+                    self.temps += 1;
                     let argc = self.explist(')')?;
+                    self.temps -= 1;
                     self.emit(Instruction::Call(argc));
-                } else {
-                    self.expr()?;
-                    self.expect(TokenKind::Single(']'))?;
+                } else if t.kind == TokenKind::Single('.') {
+                    let i = self.member()?;
+                    self.emit(i);
+                    postfix = true;
+                } else if !self.subscript()? {
                     self.emit(Instruction::Get);
                 }
             } else if let Some((lp, rp)) = self.pwr_infix(ttext.as_str()) {
@@ -190,15 +439,65 @@ impl<V: VM> Compiler<V> {
                     break;
                 }
                 self.pop()?;
-                let i = self.compile_operator(t);
-                self.expr_p(rp)?;
-                self.emit(i);
+                if ttext == "?" {
+                    let cjmp = self.emit(Instruction::Cjmp(0));
+                    self.expr()?;
+                    self.expect(TokenKind::Single(':'))?;
+                    let jmp = self.emit(Instruction::Jmp(0));
+                    let address = self.here()?;
+                    self.patch(cjmp, Instruction::Cjmp(address));
+                    self.expr_p(rp)?;
+                    let address = self.here()?;
+                    self.patch(jmp, Instruction::Jmp(address));
+                } else if ttext == "and" || ttext == "&&" {
+                    let jmp = self.emit(Instruction::And(0));
+                    self.expr_p(rp)?;
+                    let address = self.here()?;
+                    self.patch(jmp, Instruction::And(address));
+                } else if ttext == "or" || ttext == "||" {
+                    let jmp = self.emit(Instruction::Or(0));
+                    self.expr_p(rp)?;
+                    let address = self.here()?;
+                    self.patch(jmp, Instruction::Or(address));
+                } else {
+                    let i = self.compile_operator(t);
+                    self.temps += 1;
+                    self.expr_p(rp)?;
+                    self.temps -= 1;
+                    self.emit(i);
+                }
             } else {
                 return Err(self.error_unexpected(t));
             }
         }
         Ok(())
     }
+    fn interpolation(&mut self, token: Token) -> CResult<()> {
+        let literal = self.compile_literal(token)?;
+        let idx = self.vm.rodata_literal(literal);
+        self.emit(Instruction::Konst(idx));
+        loop {
+            self.temps += 1;
+            self.expr()?;
+            self.temps -= 1;
+            self.emit(Instruction::Str);
+            self.emit(Instruction::Add);
+            let token = self.pop()?;
+            if token.kind != TokenKind::Interpolation && token.kind != TokenKind::Literal {
+                return Err(self.error_unexpected(token));
+            }
+            let literal = self.compile_literal(token)?;
+            if !literal.is_empty() {
+                let idx = self.vm.rodata_literal(literal);
+                self.emit(Instruction::Konst(idx));
+                self.emit(Instruction::Add);
+            }
+            if token.kind == TokenKind::Literal {
+                break;
+            }
+        }
+        Ok(())
+    }
     fn explist(&mut self, end: char) -> CResult<usize> {
         if self.peek()?.kind == TokenKind::Single(end) {
             self.pop()?;
@@ -207,16 +506,36 @@ impl<V: VM> Compiler<V> {
             let mut count = 0;
             loop {
                 self.expr()?;
-                count = count + 1;
+                count += 1;
+                self.temps += 1;
                 if self.peek()?.kind == TokenKind::Single(end) {
                     break;
                 }
                 self.expect(TokenKind::Single(','))?;
             }
+            self.temps -= count;
             self.pop()?;
             Ok(count)
         }
     }
+    fn entrylist(&mut self) -> CResult<usize> {
+        let mut count = 0;
+        while !self.peek()?.is('}') {
+            self.expr()?;
+            self.expect(TokenKind::Single(':'))?;
+            self.temps += 1;
+            self.expr()?;
+            self.temps += 1;
+            count += 1;
+            if self.peek()?.is('}') {
+                break;
+            }
+            self.expect(TokenKind::Single(','))?;
+        }
+        self.temps -= 2 * count;
+        self.pop()?;
+        Ok(count)
+    }
     pub(crate) fn new(text: Text, scanner: Scanner, vm: V) -> Compiler<V> {
         Compiler {
             scanner,
@@ -225,8 +544,24 @@ impl<V: VM> Compiler<V> {
             token_buffer: None,
             scopes: vec![Scope::default()],
             offset: 0,
+            loops: vec![],
+            tries: 0,
+            temps: 0,
+            functions: vec![],
+            fields: HashMap::new(),
+            prelude: Scope::default(),
+            statics: Scope::default(),
+            declared: HashSet::new(),
+            forwards: Scope::default(),
+            imports: HashMap::new(),
+            modules: HashMap::new(),
+            loading: vec![],
+            paths: vec![],
         }
     }
+    pub(crate) fn paths(&mut self, paths: Vec<PathBuf>) {
+        self.paths = paths;
+    }
     fn libs(&mut self) -> CResult<()> {
         // print
         let idx = self.vm.rodata_native(crate::native::bakht_print, 1);
@@ -240,10 +575,39 @@ impl<V: VM> Compiler<V> {
         // pop
         let idx = self.vm.rodata_native(crate::native::bakht_pop, 1);
         self.register_const("pop".to_string(), idx)?;
+        // keys
+        let idx = self.vm.rodata_native(crate::native::bakht_keys, 1);
+        self.register_const("keys".to_string(), idx)?;
+        // values
+        let idx = self.vm.rodata_native(crate::native::bakht_values, 1);
+        self.register_const("values".to_string(), idx)?;
+        // has
+        let idx = self.vm.rodata_native(crate::native::bakht_has, 2);
+        self.register_const("has".to_string(), idx)?;
+        // remove
+        let idx = self.vm.rodata_native(crate::native::bakht_remove, 2);
+        self.register_const("remove".to_string(), idx)?;
+        // next
+        let idx = self.vm.rodata_native(crate::native::bakht_next, 1);
+        self.register_const("next".to_string(), idx)?;
+        // send
+        let idx = self.vm.rodata_native(crate::native::bakht_send, 2);
+        self.register_const("send".to_string(), idx)?;
+        // done
+        let idx = self.vm.rodata_native(crate::native::bakht_done, 1);
+        self.register_const("done".to_string(), idx)?;
+        // Error
+        let fields = ["kind", "message"]
+            .map(|name| self.intern(name.to_string()))
+            .to_vec();
+        let idx = self.vm.rodata_struct(fields);
+        self.vm.error_struct(idx);
+        self.register_const("Error".to_string(), idx)?;
         Ok(())
     }
     pub(crate) fn compile(&mut self) -> CResult<()> {
         self.libs()?;
+        self.prelude = self.scopes[0].clone();
         self.source()
     }
     pub(crate) fn vm(self) -> V {
@@ -253,40 +617,160 @@ impl<V: VM> Compiler<V> {
         if let AssignCallState::Identifier(token) = state {
             let i = self.compile_load_id(token)?;
             self.emit(i);
+        } else if let AssignCallState::Field(field) = state {
+            self.emit(Instruction::Getfield(field));
         } else if state == AssignCallState::Index {
             self.emit(Instruction::Get);
         }
         Ok(())
     }
+    fn subscript(&mut self) -> CResult<bool> {
+        self.temps += 1;
+        if self.peek()?.is(':') {
+            self.emit(Instruction::Nil);
+        } else {
+            self.expr()?;
+        }
+        if self.peek()?.is(']') {
+            self.temps -= 1;
+            self.pop()?;
+            return Ok(false);
+        }
+        self.expect(TokenKind::Single(':'))?;
+        self.temps += 1;
+        if self.peek()?.is(':') || self.peek()?.is(']') {
+            self.emit(Instruction::Nil);
+        } else {
+            self.expr()?;
+        }
+        self.temps += 1;
+        if self.peek()?.is(':') {
+            self.pop()?;
+        }
+        if self.peek()?.is(']') {
+            self.emit(Instruction::Nil);
+        } else {
+            self.expr()?;
+        }
+        self.temps -= 3;
+        self.expect(TokenKind::Single(']'))?;
+        self.emit(Instruction::Slice);
+        Ok(true)
+    }
+    fn member(&mut self) -> CResult<Instruction> {
+        let id = self.expect(TokenKind::Identifier)?;
+        if self.peek()?.is('(') {
+            let name = self.get_token_text(id);
+            Ok(Instruction::Method(self.intern(name)))
+        } else {
+            match self.fields.get(&self.get_token_text(id)) {
+                Some(field) => Ok(Instruction::Getfield(*field)),
+                None => Err(Error::UnknownIdentifier(id)),
+            }
+        }
+    }
+    fn intern(&mut self, name: String) -> usize {
+        let count = self.fields.len();
+        *self.fields.entry(name).or_insert_with_key(|name| {
+            self.vm.rodata_name(name.clone());
+            count
+        })
+    }
     fn get_token_text(&self, token: Token) -> String {
         token.text(self.text.clone())
     }
-    fn get_id(&mut self, token: Token) -> CResult<(usize, bool)> {
+    fn get_local(&self, level: usize, name: &str) -> Option<usize> {
+        let from = self.functions[level].depth;
+        let to = match self.functions.get(level + 1) {
+            Some(f) => f.depth,
+            None => self.scopes.len(),
+        };
+        self.scopes[from..to]
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(name).cloned())
+    }
+    fn get_upvalue(&mut self, level: usize, name: &str) -> Option<usize> {
+        if level == 0 {
+            return None;
+        }
+        let upvalue = if let Some(idx) = self.get_local(level - 1, name) {
+            (true, idx)
+        } else {
+            (false, self.get_upvalue(level - 1, name)?)
+        };
+        let upvalues = &mut self.functions[level].upvalues;
+        match upvalues.iter().position(|u| *u == upvalue) {
+            Some(idx) => Some(idx),
+            None => {
+                upvalues.push(upvalue);
+                Some(upvalues.len() - 1)
+            }
+        }
+    }
+    fn get_id(&mut self, token: Token) -> CResult<Variable> {
         let name = self.get_token_text(token);
-        for (i, c) in self.scopes.iter().enumerate().rev() {
-            if let Some(idx) = c.get(&name) {
-                return Ok((*idx, i == 0));
+        if let Some(level) = self.functions.len().checked_sub(1) {
+            if let Some(idx) = self.get_local(level, &name) {
+                return Ok(Variable::Local(idx));
+            }
+            if let Some(idx) = self.get_upvalue(level, &name) {
+                return Ok(Variable::Upvalue(idx));
             }
         }
-        return Err(Error::UnknownIdentifier(token));
+        if let Some(module) = self.imports.get(&name) {
+            return Ok(Variable::Module(module.clone()));
+        }
+        if let Some(slot) = self.statics.get(&name) {
+            return Ok(Variable::Static(*slot));
+        }
+        if let Some(idx) = self.scopes[0].get(&name) {
+            return Ok(Variable::Global(*idx));
+        }
+        if self.declared.contains(&name) {
+            let slot = match self.forwards.get(&name) {
+                Some(slot) => *slot,
+                None => {
+                    let slot = self.vm.global();
+                    self.forwards.insert(name, slot);
+                    slot
+                }
+            };
+            return Ok(Variable::Forward(slot));
+        }
+        Err(Error::UnknownIdentifier(token))
     }
     fn compile_load_id(&mut self, token: Token) -> CResult<Instruction> {
-        let (idx, is_global) = self.get_id(token)?;
-        if is_global {
-            Ok(Instruction::Konst(idx))
-        } else {
-            Ok(Instruction::Load(idx))
+        match self.get_id(token)? {
+            Variable::Local(idx) => Ok(Instruction::Load(idx)),
+            Variable::Upvalue(idx) => Ok(Instruction::Loadup(idx)),
+            Variable::Static(slot) | Variable::Forward(slot) => Ok(Instruction::Gload(slot)),
+            Variable::Global(idx) => Ok(Instruction::Konst(idx)),
+            Variable::Module(module) => {
+                self.expect(TokenKind::Single('.'))?;
+                let id = self.expect(TokenKind::Identifier)?;
+                let name = self.get_token_text(id);
+                if let Some(idx) = module.consts.get(&name) {
+                    Ok(Instruction::Konst(*idx))
+                } else if let Some(slot) = module.statics.get(&name) {
+                    Ok(Instruction::Gload(*slot))
+                } else {
+                    Err(Error::UnknownIdentifier(id))
+                }
+            }
         }
     }
     fn compile_store_id(&mut self, token: Token) -> CResult<Instruction> {
-        let (idx, is_global) = self.get_id(token)?;
-        if is_global {
-            Err(self.error_immutable(token))
-        } else {
-            Ok(Instruction::Store(idx))
+        match self.get_id(token)? {
+            Variable::Local(idx) => Ok(Instruction::Store(idx)),
+            Variable::Upvalue(idx) => Ok(Instruction::Storeup(idx)),
+            Variable::Static(slot) => Ok(Instruction::Gstore(slot)),
+            Variable::Global(_) | Variable::Forward(_) | Variable::Module(_) => {
+                Err(self.error_immutable(token))
+            }
         }
     }
-    fn assign_call(&mut self) -> CResult<()> {
+    fn assign_call(&mut self, value: bool) -> CResult<bool> {
         let tkn = self.pop()?;
         let mut state = if tkn.is('(') {
             self.expr()?;
@@ -297,7 +781,13 @@ impl<V: VM> Compiler<V> {
             self.emit(Instruction::Anew(count));
             AssignCallState::InitialRvalue
         } else if tkn.kind == TokenKind::Identifier {
-            AssignCallState::Identifier(tkn)
+            if let Ok(Variable::Module(_)) = self.get_id(tkn) {
+                let i = self.compile_load_id(tkn)?;
+                self.emit(i);
+                AssignCallState::InitialRvalue
+            } else {
+                AssignCallState::Identifier(tkn)
+            }
         } else {
             return Err(self.error_unexpected(tkn));
         };
@@ -307,9 +797,13 @@ impl<V: VM> Compiler<V> {
             if tkn.is('=') {
                 self.pop()?;
                 if state.endable() {
+                    self.temps += state.depth();
                     self.expr()?;
+                    self.temps -= state.depth();
                     let i = if let AssignCallState::Identifier(token) = state {
                         self.compile_store_id(token)?
+                    } else if let AssignCallState::Field(field) = state {
+                        Instruction::Setfield(field)
                     } else {
                         Instruction::Set
                     };
@@ -318,28 +812,76 @@ impl<V: VM> Compiler<V> {
                 } else {
                     return Err(self.error_unexpected(tkn));
                 }
+            } else if let Some(operator) = self.compile_assign_operator(tkn) {
+                self.pop()?;
+                if !state.endable() {
+                    return Err(self.error_unexpected(tkn));
+                }
+                let store = match state {
+                    AssignCallState::Identifier(token) => {
+                        let i = self.compile_load_id(token)?;
+                        self.emit(i);
+                        self.compile_store_id(token)?
+                    }
+                    AssignCallState::Field(field) => {
+                        self.emit(Instruction::Dup(1));
+                        self.emit(Instruction::Getfield(field));
+                        Instruction::Setfield(field)
+                    }
+                    _ => {
+                        self.emit(Instruction::Dup(2));
+                        self.emit(Instruction::Get);
+                        Instruction::Set
+                    }
+                };
+                self.temps += state.depth() + 1;
+                self.expr()?;
+                self.temps -= state.depth() + 1;
+                self.emit(operator);
+                self.emit(store);
+                break;
             } else if tkn.is('[') {
                 self.pop()?;
                 self.flush_lvalue(state)?;
-                self.expr()?;
-                self.expect(TokenKind::Single(']'))?;
-                state = AssignCallState::Index;
+                state = if self.subscript()? {
+                    AssignCallState::InitialRvalue
+                } else {
+                    AssignCallState::Index
+                };
+            } else if tkn.is('.') {
+                self.pop()?;
+                self.flush_lvalue(state)?;
+                state = match self.member()? {
+                    Instruction::Getfield(field) => AssignCallState::Field(field),
+                    i => {
+                        self.emit(i);
+                        AssignCallState::InitialRvalue
+                    }
+                };
             } else if tkn.is('(') {
                 self.pop()?;
                 self.flush_lvalue(state)?;
+                self.temps += 1;
                 let count = self.explist(')')?;
+                self.temps -= 1;
                 self.emit(Instruction::Call(count));
                 state = AssignCallState::Call;
+            } else if value
+                && (tkn.is('}')
+                    || state != AssignCallState::Call
+                    || self.pwr_infix(&self.get_token_text(tkn)).is_some())
+            {
+                self.flush_lvalue(state)?;
+                self.operators(0, true)?;
+                return Ok(true);
+            } else if state == AssignCallState::Call {
+                self.emit(Instruction::Pop(1));
+                break;
             } else {
-                if state == AssignCallState::Call {
-                    self.emit(Instruction::Pop(1));
-                    break;
-                } else {
-                    return Err(self.error_unexpected(tkn));
-                }
+                return Err(self.error_unexpected(tkn));
             }
         }
-        Ok(())
+        Ok(false)
     }
     fn block(&mut self, end: TokenKind) -> CResult<()> {
         self.new_scope();
@@ -348,8 +890,6 @@ impl<V: VM> Compiler<V> {
         }
         self.close_scope();
         self.pop()?;
-        self.emit(Instruction::Nil);
-        self.emit(Instruction::Ret);
         Ok(())
     }
     fn new_scope(&mut self) {
@@ -363,11 +903,20 @@ impl<V: VM> Compiler<V> {
         }
         self.scopes.pop();
     }
-    fn curscope<'a>(&'a mut self) -> &'a mut Scope {
+    fn leave_scopes(&mut self, depth: usize) {
+        let size: usize = self.scopes[depth..].iter().map(|s| s.len()).sum();
+        if size > 0 {
+            self.emit(Instruction::Pop(size));
+        }
+    }
+    fn curscope(&mut self) -> &mut Scope {
         self.scopes.last_mut().unwrap()
     }
     fn register_decl(&mut self, token: Token) -> CResult<()> {
         let name = self.get_token_text(token);
+        self.register_local(name)
+    }
+    fn register_local(&mut self, name: String) -> CResult<()> {
         if self.curscope().get(&name).is_some() {
             return Err(Error::MultipleDefinition(name));
         }
@@ -377,21 +926,86 @@ impl<V: VM> Compiler<V> {
         Ok(())
     }
     fn register_const(&mut self, name: String, idx: usize) -> CResult<()> {
-        if self.scopes.first().unwrap().get(&name).is_some() {
+        let defined = self.statics.contains_key(&name) || self.imports.contains_key(&name);
+        let globals = self.scopes.first_mut().unwrap();
+        if defined || globals.get(&name).is_some() {
             return Err(Error::MultipleDefinition(name));
         }
-        self.curscope().insert(name, idx);
+        globals.insert(name, idx);
         Ok(())
     }
     fn var_decl(&mut self) -> CResult<()> {
+        if self.peek()?.is('[') {
+            return self.destructure();
+        }
         let id = self.expect(TokenKind::Identifier)?;
-        self.register_decl(id)?;
         if self.peek()?.is('=') {
             self.pop()?;
             self.expr()?;
         } else {
             self.emit(Instruction::Nil);
         }
+        self.register_decl(id)
+    }
+    fn destructure(&mut self) -> CResult<()> {
+        self.pop()?;
+        let mut ids = vec![];
+        let mut rest = false;
+        while !self.peek()?.is(']') {
+            let token = self.peek()?;
+            if rest {
+                return Err(self.error_unexpected(token));
+            }
+            if self.get_token_text(token) == ".." {
+                self.pop()?;
+                self.expect(TokenKind::Single('.'))?;
+                rest = true;
+            }
+            ids.push(self.expect(TokenKind::Identifier)?);
+            if !self.peek()?.is(']') {
+                self.expect(TokenKind::Single(','))?;
+            }
+        }
+        self.pop()?;
+        self.expect(TokenKind::Single('='))?;
+        self.expr()?;
+        let count = ids.len() - rest as usize;
+        self.emit(Instruction::Unpack(count << 1 | rest as usize));
+        for id in ids {
+            self.register_decl(id)?;
+        }
+        Ok(())
+    }
+    fn global_decl(&mut self) -> CResult<()> {
+        let id = self.expect(TokenKind::Identifier)?;
+        let name = self.get_token_text(id);
+        if self.scopes[0].contains_key(&name)
+            || self.statics.contains_key(&name)
+            || self.imports.contains_key(&name)
+        {
+            return Err(Error::MultipleDefinition(name));
+        }
+        let slot = self.vm.global();
+        if self.peek()?.is('=') {
+            self.pop()?;
+            self.functions.push(FnScope {
+                depth: self.scopes.len(),
+                upvalues: vec![],
+                generator: None,
+            });
+            self.new_scope();
+            let idx = self.vm.rodata_function(0, 0, false, false);
+            self.expr()?;
+            self.emit(Instruction::Gstore(slot));
+            self.emit(Instruction::Nil);
+            self.emit(Instruction::Ret);
+            self.scopes.pop();
+            if let Some(token) = self.functions.pop().unwrap().generator {
+                return Err(self.error_unexpected(token));
+            }
+            self.vm.initializer(idx);
+        }
+        self.statics.insert(name, slot);
         Ok(())
     }
     fn stmt(&mut self) -> CResult<()> {
@@ -408,72 +1022,716 @@ impl<V: VM> Compiler<V> {
         } else if self.peek()?.kind == TokenKind::Return {
             self.pop()?;
             self.expr()?;
-            self.emit(Instruction::Ret);
-            while !self.peek()?.is('}') && self.peek()?.kind != TokenKind::EOF {
+            let mut count = 1;
+            while self.peek()?.is(',') {
                 self.pop()?;
+                self.temps += 1;
+                self.expr()?;
+                count += 1;
+            }
+            self.temps -= count - 1;
+            if count > 1 {
+                self.emit(Instruction::Anew(count));
             }
+            self.emit(Instruction::Ret);
+        } else if self.peek()?.kind == TokenKind::Throw {
+            self.pop()?;
+            self.expr()?;
+            self.emit(Instruction::Throw);
+        } else if self.peek()?.kind == TokenKind::Try {
+            self.try_stmt()?;
+        } else if self.peek()?.kind == TokenKind::Yield {
+            self.expr()?;
+            self.emit(Instruction::Pop(1));
+        } else if self.peek()?.kind == TokenKind::Match {
+            let token = self.pop()?;
+            self.match_expr(token, true)?;
+            self.emit(Instruction::Pop(1));
         } else if self.peek()?.kind == TokenKind::If {
             self.if_stmt()?;
+        } else if self.peek()?.kind == TokenKind::While {
+            self.while_stmt()?;
+        } else if self.peek()?.kind == TokenKind::For {
+            self.for_stmt()?;
+        } else if self.peek()?.kind == TokenKind::Fn {
+            self.pop()?;
+            let id = self.expect(TokenKind::Identifier)?;
+            self.register_decl(id)?;
+            self.closure()?;
+        } else if self.peek()?.kind == TokenKind::Break {
+            let token = self.pop()?;
+            let (depth, tries) = match self.loops.last() {
+                Some(l) => (l.depth, l.tries),
+                None => return Err(self.error_unexpected(token)),
+            };
+            self.leave_tries(tries);
+            self.leave_scopes(depth);
+            let jmp = self.emit(Instruction::Jmp(0));
+            self.loops.last_mut().unwrap().breaks.push(jmp);
+        } else if self.peek()?.kind == TokenKind::Continue {
+            let token = self.pop()?;
+            let (depth, tries, start) = match self.loops.last() {
+                Some(l) => (l.depth, l.tries, l.start),
+                None => return Err(self.error_unexpected(token)),
+            };
+            self.leave_tries(tries);
+            self.leave_scopes(depth);
+            self.emit(Instruction::Jmp(self.target(start)?));
         } else {
-            self.assign_call()?;
+            self.assign_call(false)?;
         }
         Ok(())
     }
-    fn seek(&mut self, kind: TokenKind) -> CResult<bool> {
-        Ok(true)
+    fn open_temps(&mut self) -> (usize, Vec<Loop>) {
+        let temps = std::mem::replace(&mut self.temps, 0);
+        self.offset += temps;
+        if temps > 0 {
+            (temps, std::mem::take(&mut self.loops))
+        } else {
+            (temps, vec![])
+        }
+    }
+    fn close_temps(&mut self, (temps, loops): (usize, Vec<Loop>)) {
+        self.offset -= temps;
+        self.temps = temps;
+        if temps > 0 {
+            self.loops = loops;
+        }
+    }
+    fn match_expr(&mut self, token: Token, statement: bool) -> CResult<()> {
+        self.expr()?;
+        self.expect(TokenKind::Single('{'))?;
+        let saved = self.open_temps();
+        self.new_scope();
+        let subject = self.offset;
+        self.register_local("(match)".to_string())?;
+        let mut exits = vec![];
+        let mut irrefutable = false;
+        while !self.peek()?.is('}') {
+            if irrefutable {
+                let token = self.peek()?;
+                return Err(self.error_unexpected(token));
+            }
+            let pattern = self.pattern()?;
+            irrefutable = matches!(pattern, Pattern::Wildcard | Pattern::Binding(_));
+            let arrow = self.expect(TokenKind::Double)?;
+            if self.get_token_text(arrow) != "=>" {
+                return Err(self.error_unexpected(arrow));
+            }
+            self.new_scope();
+            let mut fails = vec![];
+            let mut bindings = vec![];
+            self.compile_pattern(&pattern, subject, vec![], &mut fails, &mut bindings)?;
+            for (id, path) in bindings {
+                self.load_path(subject, &path);
+                self.register_decl(id)?;
+            }
+            let block = statement && self.peek()?.is('{');
+            if block {
+                self.pop()?;
+                self.block(TokenKind::Single('}'))?;
+                self.emit(Instruction::Nil);
+            } else {
+                self.expr()?;
+            }
+            self.emit(Instruction::Store(subject));
+            self.close_scope();
+            exits.push(self.emit(Instruction::Jmp(0)));
+            let address = self.here()?;
+            for fail in fails {
+                self.patch(fail, Instruction::Cjmp(address));
+            }
+            if self.peek()?.is(',') {
+                self.pop()?;
+            } else if !block && !self.peek()?.is('}') {
+                let token = self.peek()?;
+                return Err(self.error_unexpected(token));
+            }
+        }
+        if !irrefutable {
+            return Err(Error::RefutableMatch(token));
+        }
+        self.pop()?;
+        let address = self.here()?;
+        for exit in exits {
+            self.patch(exit, Instruction::Jmp(address));
+        }
+        self.scopes.pop();
+        self.offset -= 1;
+        self.close_temps(saved);
+        Ok(())
+    }
+    fn pattern(&mut self) -> CResult<Pattern> {
+        let token = self.pop()?;
+        if token.kind == TokenKind::Identifier {
+            if self.get_token_text(token) == "_" {
+                Ok(Pattern::Wildcard)
+            } else {
+                Ok(Pattern::Binding(token))
+            }
+        } else if token.is('[') {
+            let mut elements = vec![];
+            while !self.peek()?.is(']') {
+                elements.push(self.pattern()?);
+                if !self.peek()?.is(']') {
+                    self.expect(TokenKind::Single(','))?;
+                }
+            }
+            self.pop()?;
+            Ok(Pattern::Array(elements))
+        } else if token.is('-') {
+            Ok(Pattern::Literal(self.expect(TokenKind::Number)?, true))
+        } else if matches!(
+            token.kind,
+            TokenKind::Number
+                | TokenKind::Literal
+                | TokenKind::True
+                | TokenKind::False
+                | TokenKind::Nil
+        ) {
+            Ok(Pattern::Literal(token, false))
+        } else {
+            Err(self.error_unexpected(token))
+        }
+    }
+    fn load_path(&mut self, subject: usize, path: &[usize]) {
+        self.emit(Instruction::Load(subject));
+        for index in path {
+            let idx = self.vm.rodata_int(*index as i64);
+            self.emit(Instruction::Konst(idx));
+            self.emit(Instruction::Get);
+        }
+    }
+    fn compile_pattern(
+        &mut self,
+        pattern: &Pattern,
+        subject: usize,
+        path: Vec<usize>,
+        fails: &mut Vec<usize>,
+        bindings: &mut Vec<(Token, Vec<usize>)>,
+    ) -> CResult<()> {
+        match pattern {
+            Pattern::Wildcard => (),
+            Pattern::Binding(id) => bindings.push((*id, path)),
+            Pattern::Literal(token, negative) => {
+                self.load_path(subject, &path);
+                let i = self.compile_atom(*token)?;
+                self.emit(i);
+                if *negative {
+                    let idx = self.vm.rodata_int(-1);
+                    self.emit(Instruction::Konst(idx));
+                    self.emit(Instruction::Mult);
+                }
+                self.emit(Instruction::Eq);
+                fails.push(self.emit(Instruction::Cjmp(0)));
+            }
+            Pattern::Array(elements) => {
+                self.load_path(subject, &path);
+                self.emit(Instruction::Shape(elements.len() << 1));
+                fails.push(self.emit(Instruction::Cjmp(0)));
+                for (i, element) in elements.iter().enumerate() {
+                    let mut path = path.clone();
+                    path.push(i);
+                    self.compile_pattern(element, subject, path, fails, bindings)?;
+                }
+            }
+        }
+        Ok(())
+    }
+    fn leave_tries(&mut self, tries: usize) {
+        if self.tries > tries {
+            self.emit(Instruction::Untry(self.tries - tries));
+        }
+    }
+    fn try_stmt(&mut self) -> CResult<()> {
+        self.pop()?;
+        let handler = self.emit(Instruction::Try(0));
+        self.expect(TokenKind::Single('{'))?;
+        self.tries += 1;
+        self.block(TokenKind::Single('}'))?;
+        self.tries -= 1;
+        self.emit(Instruction::Untry(1));
+        let exit = self.emit(Instruction::Jmp(0));
+        self.patch(handler, Instruction::Try(self.here()?));
+        self.expect(TokenKind::Catch)?;
+        let id = self.expect(TokenKind::Identifier)?;
+        self.new_scope();
+        self.register_decl(id)?;
+        self.expect(TokenKind::Single('{'))?;
+        self.block(TokenKind::Single('}'))?;
+        self.close_scope();
+        self.patch(exit, Instruction::Jmp(self.here()?));
+        Ok(())
     }
     fn if_stmt(&mut self) -> CResult<()> {
         self.pop()?;
         self.expr()?;
+        let cjmp = self.emit(Instruction::Cjmp(0));
+        self.expect(TokenKind::Single('{'))?;
+        self.block(TokenKind::Single('}'))?;
+        if self.peek()?.kind == TokenKind::Else {
+            self.pop()?;
+            let jmp = self.emit(Instruction::Jmp(0));
+            let address = self.here()?;
+            self.patch(cjmp, Instruction::Cjmp(address));
+            if self.peek()?.kind == TokenKind::If {
+                self.if_stmt()?;
+            } else {
+                self.expect(TokenKind::Single('{'))?;
+                self.block(TokenKind::Single('}'))?;
+            }
+            let address = self.here()?;
+            self.patch(jmp, Instruction::Jmp(address));
+        } else {
+            let address = self.here()?;
+            self.patch(cjmp, Instruction::Cjmp(address));
+        }
+        Ok(())
+    }
+    fn if_expr(&mut self) -> CResult<()> {
+        self.expr()?;
+        let cjmp = self.emit(Instruction::Cjmp(0));
+        let saved = self.open_temps();
+        self.value_block()?;
+        let jmp = self.emit(Instruction::Jmp(0));
+        let address = self.here()?;
+        self.patch(cjmp, Instruction::Cjmp(address));
+        self.expect(TokenKind::Else)?;
+        if self.peek()?.kind == TokenKind::If {
+            self.pop()?;
+            self.if_expr()?;
+        } else {
+            self.value_block()?;
+        }
+        let address = self.here()?;
+        self.patch(jmp, Instruction::Jmp(address));
+        self.close_temps(saved);
+        Ok(())
+    }
+    fn value_block(&mut self) -> CResult<()> {
         self.expect(TokenKind::Single('{'))?;
+        self.emit(Instruction::Nil);
+        self.new_scope();
+        let result = self.offset;
+        self.register_local("(value)".to_string())?;
         self.new_scope();
-        while self.peek()?.kind != TokenKind::Single('}') || self.peek()?.kind != TokenKind::EOF {}
-        self.expect(TokenKind::Single('}'))?;
+        loop {
+            let token = self.peek()?;
+            let value = match token.kind {
+                TokenKind::Identifier | TokenKind::Single('(') | TokenKind::Single('[') => {
+                    self.assign_call(true)?
+                }
+                TokenKind::Fn => {
+                    self.pop()?;
+                    if self.peek()?.kind == TokenKind::Identifier {
+                        let id = self.pop()?;
+                        self.register_decl(id)?;
+                        self.closure()?;
+                        false
+                    } else {
+                        self.closure()?;
+                        self.operators(0, false)?;
+                        true
+                    }
+                }
+                TokenKind::If | TokenKind::Match if self.ends_block() => {
+                    self.expr()?;
+                    true
+                }
+                TokenKind::Return | TokenKind::Throw | TokenKind::Break | TokenKind::Continue => {
+                    self.stmt()?;
+                    if self.peek()?.is('}') {
+                        break;
+                    }
+                    false
+                }
+                TokenKind::Let
+                | TokenKind::Try
+                | TokenKind::While
+                | TokenKind::For
+                | TokenKind::If
+                | TokenKind::Match
+                | TokenKind::Single('{') => {
+                    self.stmt()?;
+                    false
+                }
+                _ => {
+                    self.expr()?;
+                    true
+                }
+            };
+            if value {
+                if self.peek()?.is('}') {
+                    break;
+                }
+                self.emit(Instruction::Pop(1));
+            }
+        }
+        self.pop()?;
+        self.emit(Instruction::Store(result));
+        self.close_scope();
+        self.scopes.pop();
+        self.offset -= 1;
         Ok(())
     }
-    fn paramlist(&mut self) -> CResult<u8> {
-        self.expect(TokenKind::Single('('))?;
-        if self.peek()?.is(')') {
+    fn ends_block(&self) -> bool {
+        let mut scanner = self.scanner.clone();
+        let mut depth = 0usize;
+        loop {
+            let token = scanner.next();
+            if token.kind == TokenKind::EOF || token.is_error() {
+                return false;
+            } else if token.is('{') || token.is('(') || token.is('[') {
+                depth += 1;
+            } else if token.is(')') || token.is(']') {
+                depth = depth.saturating_sub(1);
+            } else if token.is('}') {
+                depth = depth.saturating_sub(1);
+                if depth == 0 {
+                    let next = scanner.next();
+                    if next.kind != TokenKind::Else {
+                        return next.is('}');
+                    }
+                }
+            }
+        }
+    }
+    fn while_stmt(&mut self) -> CResult<()> {
+        self.pop()?;
+        let start = self.vm.address();
+        self.expr()?;
+        let cjmp = self.emit(Instruction::Cjmp(0));
+        self.loops.push(Loop {
+            start,
+            depth: self.scopes.len(),
+            tries: self.tries,
+            breaks: vec![],
+        });
+        self.expect(TokenKind::Single('{'))?;
+        self.block(TokenKind::Single('}'))?;
+        self.emit(Instruction::Jmp(self.target(start)?));
+        let address = self.here()?;
+        self.patch(cjmp, Instruction::Cjmp(address));
+        for jmp in self.loops.pop().unwrap().breaks {
+            self.patch(jmp, Instruction::Jmp(address));
+        }
+        Ok(())
+    }
+    fn for_stmt(&mut self) -> CResult<()> {
+        self.pop()?;
+        let id = self.expect(TokenKind::Identifier)?;
+        self.expect(TokenKind::In)?;
+        self.new_scope();
+        let from = self.offset;
+        self.expr()?;
+        self.register_local("(iterable)".to_string())?;
+        let token = self.peek()?;
+        let ranged = self.get_token_text(token).as_str() == "..";
+        let start;
+        let exit;
+        if ranged {
             self.pop()?;
-            Ok(0)
+            self.expr()?;
+            self.register_local("(end)".to_string())?;
+            start = self.vm.address();
+            self.emit(Instruction::Load(from));
+            self.emit(Instruction::Load(from + 1));
+            self.emit(Instruction::Lt);
+            exit = self.emit(Instruction::Cjmp(0));
         } else {
-            let id = self.expect(TokenKind::Identifier)?;
-            self.register_decl(id)?;
-            let mut param_count = 1;
-            while self.peek()?.is(',') {
+            let zero = self.vm.rodata_int(0);
+            self.emit(Instruction::Konst(zero));
+            self.register_local("(index)".to_string())?;
+            start = self.vm.address();
+            exit = self.emit(Instruction::Next(0));
+        }
+        self.loops.push(Loop {
+            start,
+            depth: self.scopes.len(),
+            tries: self.tries,
+            breaks: vec![],
+        });
+        self.new_scope();
+        if ranged {
+            self.emit(Instruction::Load(from));
+            let one = self.vm.rodata_int(1);
+            self.emit(Instruction::Load(from));
+            self.emit(Instruction::Konst(one));
+            self.emit(Instruction::Add);
+            self.emit(Instruction::Store(from));
+        }
+        self.register_decl(id)?;
+        self.expect(TokenKind::Single('{'))?;
+        self.block(TokenKind::Single('}'))?;
+        self.close_scope();
+        self.emit(Instruction::Jmp(self.target(start)?));
+        let address = self.here()?;
+        if ranged {
+            self.patch(exit, Instruction::Cjmp(address));
+        } else {
+            self.patch(exit, Instruction::Next(address));
+        }
+        for jmp in self.loops.pop().unwrap().breaks {
+            self.patch(jmp, Instruction::Jmp(address));
+        }
+        self.close_scope();
+        Ok(())
+    }
+    fn paramlist(&mut self) -> CResult<Params> {
+        self.expect(TokenKind::Single('('))?;
+        let mut params = Params::default();
+        while !self.peek()?.is(')') {
+            let token = self.peek()?;
+            if params.rest {
+                return Err(self.error_unexpected(token));
+            }
+            if self.get_token_text(token) == ".." {
                 self.pop()?;
+                self.expect(TokenKind::Single('.'))?;
+                let id = self.expect(TokenKind::Identifier)?;
+                self.register_decl(id)?;
+                params.rest = true;
+            } else {
                 let id = self.expect(TokenKind::Identifier)?;
+                if self.peek()?.is('=') {
+                    self.pop()?;
+                    params.defaults.push(self.vm.address());
+                    self.expr()?;
+                } else if !params.defaults.is_empty() {
+                    return Err(self.error_unexpected(id));
+                } else {
+                    params.required += 1;
+                }
                 self.register_decl(id)?;
-                param_count = param_count + 1;
+                params.count += 1;
+            }
+            if !self.peek()?.is(')') {
+                self.expect(TokenKind::Single(','))?;
             }
-            self.expect(TokenKind::Single(')'))?;
-            Ok(param_count)
         }
+        self.pop()?;
+        Ok(params)
     }
-    fn function_body(&mut self) -> CResult<bool> {
-        let id = self.expect(TokenKind::Identifier)?;
-        let param_count = self.paramlist()? as usize;
-        let is_main = self.get_token_text(id).as_str() == "main";
-        let idx = self.vm.rodata_function(param_count, is_main);
-        self.register_const(self.get_token_text(id), idx)?;
-        self.expect(TokenKind::Single('{'))?;
+    fn function_body(&mut self, id: Option<Token>) -> CResult<(usize, Vec<(bool, usize)>)> {
+        let offset = std::mem::replace(&mut self.offset, 0);
+        let loops = std::mem::take(&mut self.loops);
+        let tries = std::mem::replace(&mut self.tries, 0);
+        let temps = std::mem::replace(&mut self.temps, 0);
+        self.functions.push(FnScope {
+            depth: self.scopes.len(),
+            upvalues: vec![],
+            generator: None,
+        });
         self.new_scope();
+        let params = self.paramlist()?;
+        let skip = if params.defaults.is_empty() {
+            None
+        } else {
+            if params.rest {
+                self.emit(Instruction::Anew(0));
+            }
+            Some(self.emit(Instruction::Jmp(0)))
+        };
+        let is_main = match id {
+            Some(id) => self.get_token_text(id).as_str() == "main" && self.loading.is_empty(),
+            None => false,
+        };
+        let idx = self
+            .vm
+            .rodata_function(params.count, params.required, params.rest, is_main);
+        if let Some(id) = id {
+            let name = self.get_token_text(id);
+            self.register_const(name, idx)?;
+        }
+        for default in params.defaults {
+            self.emit(Instruction::Jmp(self.target(default)?));
+        }
+        if let Some(skip) = skip {
+            let address = self.here()?;
+            self.patch(skip, Instruction::Jmp(address));
+        }
+        self.expect(TokenKind::Single('{'))?;
         self.block(TokenKind::Single('}'))?;
-        self.close_scope();
-        Ok(is_main)
+        self.emit(Instruction::Nil);
+        self.emit(Instruction::Ret);
+        self.scopes.pop();
+        let function = self.functions.pop().unwrap();
+        if function.generator.is_some() {
+            self.vm.generator(idx);
+        }
+        let upvalues = function.upvalues;
+        self.offset = offset;
+        self.loops = loops;
+        self.tries = tries;
+        self.temps = temps;
+        Ok((idx, upvalues))
     }
-    fn source(&mut self) -> CResult<()> {
+    fn closure(&mut self) -> CResult<()> {
+        let jmp = self.emit(Instruction::Jmp(0));
+        let (idx, upvalues) = self.function_body(None)?;
+        let address = self.here()?;
+        self.patch(jmp, Instruction::Jmp(address));
+        self.emit(Instruction::Konst(idx));
+        for (is_local, idx) in upvalues {
+            self.emit(Instruction::Capture(idx << 1 | is_local as usize));
+        }
+        Ok(())
+    }
+    fn struct_decl(&mut self) -> CResult<()> {
+        let id = self.expect(TokenKind::Identifier)?;
+        self.expect(TokenKind::Single('{'))?;
+        let mut fields: Vec<usize> = vec![];
+        while !self.peek()?.is('}') {
+            let field = self.expect(TokenKind::Identifier)?;
+            let name = self.get_token_text(field);
+            let field = self.intern(name.clone());
+            if fields.contains(&field) {
+                return Err(Error::MultipleDefinition(name));
+            }
+            fields.push(field);
+            if self.peek()?.is('}') {
+                break;
+            }
+            self.expect(TokenKind::Single(','))?;
+        }
+        self.pop()?;
+        let idx = self.vm.rodata_struct(fields);
+        let name = self.get_token_text(id);
+        if let Some(slot) = self.forwards.remove(&name) {
+            self.vm.define(slot, idx);
+        }
+        self.register_const(name, idx)
+    }
+    fn resolve(&self, name: &str) -> Option<PathBuf> {
+        let dir = match self.loading.last().and_then(|p| p.parent()) {
+            Some(dir) => dir.to_path_buf(),
+            None => PathBuf::new(),
+        };
+        std::iter::once(dir)
+            .chain(self.paths.iter().cloned())
+            .map(|dir| dir.join(name))
+            .find(|path| path.is_file())
+            .and_then(|path| path.canonicalize().ok())
+    }
+    fn module(&mut self, path: PathBuf) -> CResult<Rc<Module>> {
+        let source = match std::fs::read_to_string(&path) {
+            Ok(source) => source,
+            Err(_) => return Err(Error::ModuleNotFound(path.display().to_string())),
+        };
+        let text: Text = Arc::new(source.chars().collect());
+        let scanner = std::mem::replace(&mut self.scanner, Scanner::new(text.clone()));
+        let text = std::mem::replace(&mut self.text, text);
+        let token_buffer = self.token_buffer.take();
+        let scopes = std::mem::replace(&mut self.scopes, vec![self.prelude.clone()]);
+        let imports = std::mem::take(&mut self.imports);
+        let statics = std::mem::take(&mut self.statics);
+        let declared = std::mem::take(&mut self.declared);
+        let forwards = std::mem::take(&mut self.forwards);
+        self.loading.push(path);
+        let result = self.items();
+        self.loading.pop();
+        self.declared = declared;
+        self.forwards = forwards;
+        let mut module = std::mem::replace(&mut self.scopes, scopes);
+        self.scanner = scanner;
+        self.text = text;
+        self.token_buffer = token_buffer;
+        self.imports = imports;
+        let statics = std::mem::replace(&mut self.statics, statics);
+        result?;
+        Ok(Rc::new(Module {
+            consts: module.remove(0),
+            statics,
+        }))
+    }
+    fn import(&mut self) -> CResult<()> {
+        let token = self.expect(TokenKind::Literal)?;
+        let name = self.compile_literal(token)?;
+        let path = match self.resolve(&name) {
+            Some(path) => path,
+            None => return Err(Error::ModuleNotFound(name)),
+        };
+        if self.loading.contains(&path) {
+            return Err(Error::CyclicImport(name));
+        }
+        let module = match self.modules.get(&path) {
+            Some(module) => module.clone(),
+            None => {
+                let module = self.module(path.clone())?;
+                self.modules.insert(path, module.clone());
+                module
+            }
+        };
+        let alias = match Path::new(&name).file_stem() {
+            Some(stem) => stem.to_string_lossy().to_string(),
+            None => return Err(Error::ModuleNotFound(name)),
+        };
+        if self.scopes[0].contains_key(&alias)
+            || self.statics.contains_key(&alias)
+            || self.imports.contains_key(&alias)
+        {
+            return Err(Error::MultipleDefinition(alias));
+        }
+        self.imports.insert(alias, module);
+        Ok(())
+    }
+    fn predeclare(&mut self) -> HashSet<String> {
+        let mut scanner = Scanner::new(self.text.clone());
+        let mut declared = HashSet::new();
+        let mut depth = 0usize;
+        let mut fields = false;
+        let mut token = scanner.next();
+        while token.kind != TokenKind::EOF {
+            let next = scanner.next();
+            if token.is('{') {
+                depth += 1;
+            } else if token.is('}') {
+                depth = depth.saturating_sub(1);
+                fields = false;
+            } else if fields && depth == 1 && token.kind == TokenKind::Identifier {
+                self.intern(self.get_token_text(token));
+            } else if depth == 0
+                && (token.kind == TokenKind::Fn || token.kind == TokenKind::Struct)
+                && next.kind == TokenKind::Identifier
+            {
+                declared.insert(self.get_token_text(next));
+                fields = token.kind == TokenKind::Struct;
+            }
+            token = next;
+        }
+        declared
+    }
+    fn items(&mut self) -> CResult<bool> {
+        self.declared = self.predeclare();
         let mut has_main = false;
         while self.peek()?.kind != TokenKind::EOF {
             let token = self.pop()?;
             if token.kind == TokenKind::Fn {
-                has_main |= self.function_body()?;
+                let id = self.expect(TokenKind::Identifier)?;
+                let (idx, _) = self.function_body(Some(id))?;
+                if let Some(slot) = self.forwards.remove(&self.get_token_text(id)) {
+                    self.vm.define(slot, idx);
+                }
+                has_main |= self.get_token_text(id).as_str() == "main";
+            } else if token.kind == TokenKind::Struct {
+                self.struct_decl()?;
+            } else if token.kind == TokenKind::Import {
+                self.import()?;
+            } else if token.kind == TokenKind::Let {
+                self.global_decl()?;
+                while self.peek()?.is(',') {
+                    self.pop()?;
+                    self.global_decl()?;
+                }
             } else {
                 return Err(self.error_unexpected(token));
             }
         }
         self.pop()?;
-        if has_main {
+        Ok(has_main)
+    }
+    fn source(&mut self) -> CResult<()> {
+        if self.items()? {
             Ok(())
         } else {
             Err(Error::NoMainFunction)
@@ -487,13 +1745,23 @@ enum AssignCallState {
     Call,
     Identifier(Token),
     Index,
+    Field(usize),
 }
 
 impl AssignCallState {
     fn endable(&self) -> bool {
         match self {
             AssignCallState::InitialRvalue | AssignCallState::Call => false,
-            AssignCallState::Identifier(_) | AssignCallState::Index => true,
+            AssignCallState::Identifier(_) | AssignCallState::Index | AssignCallState::Field(_) => {
+                true
+            }
+        }
+    }
+    fn depth(&self) -> usize {
+        match self {
+            AssignCallState::Identifier(_) => 0,
+            AssignCallState::InitialRvalue | AssignCallState::Call | AssignCallState::Field(_) => 1,
+            AssignCallState::Index => 2,
         }
     }
 }
@@ -10,34 +10,125 @@ mod vm;
 
 use compiler::CResult;
 use scanner::Scanner;
+use std::path::PathBuf;
+use std::rc::Rc;
 use std::sync::Arc;
 use text::{Text, Token};
-use vm::{Value, BVM};
+use vm::{Array, Native, Value, BVM};
 
 #[derive(Debug, Clone)]
 pub(crate) enum Error {
     Scanner,
+    UnterminatedString(Token),
+    InvalidEscape(Token),
+    InvalidNumber(Token),
     UnexpectedToken(Token),
     Immutable(Token),
     NoMainFunction,
+    ProgramTooLarge,
     InvalidOperands,
+    NonIntegral,
+    Overflow,
     IndexOutOfBound,
     DivisionByZero,
     CallingNonFunction,
+    Arity(usize),
     UnknownIdentifier(Token),
     MultipleDefinition(String),
+    NoSuchField,
+    NoSuchMethod(String),
+    ShapeMismatch,
+    RefutableMatch(Token),
+    ModuleNotFound(String),
+    CyclicImport(String),
+    Thrown(String),
+}
+
+impl Error {
+    pub(crate) fn kind(&self) -> &'static str {
+        match self {
+            Error::Scanner => "Scanner",
+            Error::UnterminatedString(_) => "UnterminatedString",
+            Error::InvalidEscape(_) => "InvalidEscape",
+            Error::InvalidNumber(_) => "InvalidNumber",
+            Error::UnexpectedToken(_) => "UnexpectedToken",
+            Error::Immutable(_) => "Immutable",
+            Error::NoMainFunction => "NoMainFunction",
+            Error::ProgramTooLarge => "ProgramTooLarge",
+            Error::InvalidOperands => "InvalidOperands",
+            Error::NonIntegral => "NonIntegral",
+            Error::Overflow => "Overflow",
+            Error::IndexOutOfBound => "IndexOutOfBound",
+            Error::DivisionByZero => "DivisionByZero",
+            Error::CallingNonFunction => "CallingNonFunction",
+            Error::Arity(_) => "Arity",
+            Error::UnknownIdentifier(_) => "UnknownIdentifier",
+            Error::MultipleDefinition(_) => "MultipleDefinition",
+            Error::NoSuchField => "NoSuchField",
+            Error::NoSuchMethod(_) => "NoSuchMethod",
+            Error::ShapeMismatch => "ShapeMismatch",
+            Error::RefutableMatch(_) => "RefutableMatch",
+            Error::ModuleNotFound(_) => "ModuleNotFound",
+            Error::CyclicImport(_) => "CyclicImport",
+            Error::Thrown(_) => "Thrown",
+        }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Scanner => write!(f, "invalid token"),
+            Error::UnterminatedString(token) => {
+                write!(f, "unterminated string at {}", token.from)
+            }
+            Error::InvalidEscape(token) => write!(f, "invalid escape sequence at {}", token.from),
+            Error::InvalidNumber(token) => write!(f, "invalid numeric literal at {}", token.from),
+            Error::UnexpectedToken(token) => write!(f, "unexpected token at {}", token.from),
+            Error::Immutable(token) => write!(f, "assignment to constant at {}", token.from),
+            Error::NoMainFunction => write!(f, "no main function"),
+            Error::ProgramTooLarge => write!(f, "program too large"),
+            Error::InvalidOperands => write!(f, "invalid operands"),
+            Error::NonIntegral => write!(f, "non-integral operands"),
+            Error::Overflow => write!(f, "integer overflow"),
+            Error::IndexOutOfBound => write!(f, "index out of bound"),
+            Error::DivisionByZero => write!(f, "division by zero"),
+            Error::CallingNonFunction => write!(f, "calling non-function"),
+            Error::Arity(argc) => write!(f, "wrong number of arguments: {}", argc),
+            Error::UnknownIdentifier(token) => {
+                write!(f, "unknown identifier at {}", token.from)
+            }
+            Error::MultipleDefinition(name) => write!(f, "multiple definition of '{}'", name),
+            Error::NoSuchField => write!(f, "no such field"),
+            Error::NoSuchMethod(name) => write!(f, "no such method '{}'", name),
+            Error::ShapeMismatch => write!(f, "value does not match destructuring shape"),
+            Error::RefutableMatch(token) => {
+                write!(f, "match without irrefutable final arm at {}", token.from)
+            }
+            Error::ModuleNotFound(path) => write!(f, "module '{}' not found", path),
+            Error::CyclicImport(path) => write!(f, "cyclic import of '{}'", path),
+            Error::Thrown(value) => write!(f, "uncaught exception: {}", value),
+        }
+    }
 }
 
 #[derive(Default)]
 struct BakhtScript {
     vm: BVM,
+    paths: Vec<PathBuf>,
+    methods: Vec<(&'static str, String, Native, usize)>,
 }
 
+#[derive(Debug, PartialEq)]
 enum BakhtValue {
     Function,
     Boolean(bool),
-    Number(f32),
+    Int(i64),
+    Number(f64),
     Array,
+    Map,
+    Struct,
+    Coroutine,
     Nil,
     String(String),
 }
@@ -49,23 +140,44 @@ impl BakhtScript {
     fn reset(&mut self) {
         self.vm.reset();
     }
+    fn add_path(&mut self, path: impl Into<PathBuf>) {
+        self.paths.push(path.into());
+    }
+    #[allow(dead_code)]
+    pub fn add_method(&mut self, kind: &'static str, name: &str, func: Native, param_count: usize) {
+        self.methods
+            .push((kind, name.to_string(), func, param_count));
+    }
     fn load(&mut self, source: &str) -> CResult<()> {
         self.vm.reset();
         let text: Text = Arc::new(source.chars().collect());
         let scanner = Scanner::new(text.clone());
         let mut compiler = compiler::Compiler::new(text, scanner, BVM::default());
+        compiler.paths(self.paths.clone());
         compiler.compile()?;
         self.vm = compiler.vm();
-        Ok(())
+        native::methods(&mut self.vm);
+        for (kind, name, func, param_count) in self.methods.iter() {
+            self.vm.add_method(kind, name, *func, *param_count);
+        }
+        self.vm.entry();
+        self.error()
     }
     fn pop(&mut self) -> BakhtValue {
         match self.vm.pop() {
             vm::Value::String(s) => BakhtValue::String(s.to_string()),
             vm::Value::Array(_) => BakhtValue::Array,
+            vm::Value::Map(_) => BakhtValue::Map,
             vm::Value::Nil => BakhtValue::Nil,
             vm::Value::Boolean(b) => BakhtValue::Boolean(b),
+            vm::Value::Int(n) => BakhtValue::Int(n),
             vm::Value::Number(n) => BakhtValue::Number(n),
-            vm::Value::Function(_) => BakhtValue::Function,
+            vm::Value::Function(_)
+            | vm::Value::Closure(_)
+            | vm::Value::Struct(_)
+            | vm::Value::Bound(_) => BakhtValue::Function,
+            vm::Value::Instance(_) => BakhtValue::Struct,
+            vm::Value::Coroutine(_) => BakhtValue::Coroutine,
         }
     }
     fn push_nil(&mut self) {
@@ -77,35 +189,105 @@ impl BakhtScript {
             None => Ok(()),
         }
     }
+    fn fail(&mut self, error: Error) {
+        self.vm.set_error(error);
+        self.push_nil();
+    }
     fn array_push(&mut self) {
         let ele = self.vm.pop();
-        if let Value::Array(array) = self.vm.pop() {
-            array.push(ele);
+        match self.vm.pop() {
+            Value::Array(array) => array.push(ele),
+            _ => self.vm.set_error(Error::InvalidOperands),
         }
     }
     fn array_pop(&mut self) {
-        if let Value::Array(array) = self.vm.pop() {
-            if let Some(ele) = array.pop() {
-                self.vm.push(ele);
-            }
+        match self.vm.pop() {
+            Value::Array(array) => match array.pop() {
+                Some(ele) => self.vm.push(ele),
+                None => self.fail(Error::IndexOutOfBound),
+            },
+            _ => self.fail(Error::InvalidOperands),
         }
     }
     fn array_len(&mut self) {
-        if let Value::Array(array) = self.vm.pop() {
-            self.vm.push(Value::Number(array.len() as f32));
+        let len = match self.vm.pop() {
+            Value::Array(array) => array.len(),
+            Value::String(s) => s.chars().count(),
+            Value::Map(map) => map.len(),
+            _ => return self.fail(Error::InvalidOperands),
+        };
+        self.vm.push(Value::Int(len as i64));
+    }
+    fn map_keys(&mut self) {
+        match self.vm.pop() {
+            Value::Map(map) => {
+                let keys = Array::new(map.keys());
+                self.vm.push(Value::Array(Rc::new(keys)));
+            }
+            _ => self.fail(Error::InvalidOperands),
+        }
+    }
+    fn map_values(&mut self) {
+        match self.vm.pop() {
+            Value::Map(map) => {
+                let values = Array::new(map.values());
+                self.vm.push(Value::Array(Rc::new(values)));
+            }
+            _ => self.fail(Error::InvalidOperands),
+        }
+    }
+    fn map_has(&mut self) {
+        let key = self.vm.pop();
+        match self.vm.pop() {
+            Value::Map(map) => match map.has(key) {
+                Some(has) => self.vm.push(Value::Boolean(has)),
+                None => self.fail(Error::InvalidOperands),
+            },
+            _ => self.fail(Error::InvalidOperands),
+        }
+    }
+    fn map_remove(&mut self) {
+        let key = self.vm.pop();
+        match self.vm.pop() {
+            Value::Map(map) => match map.remove(key) {
+                Some(value) => self.vm.push(value),
+                None => self.fail(Error::InvalidOperands),
+            },
+            _ => self.fail(Error::InvalidOperands),
+        }
+    }
+    fn coroutine_resume(&mut self) {
+        let sent = self.vm.pop();
+        match self.vm.pop() {
+            Value::Coroutine(coroutine) => {
+                let value = self.vm.resume(&coroutine, sent);
+                self.vm.push(value.unwrap_or(Value::Nil));
+            }
+            _ => self.fail(Error::InvalidOperands),
+        }
+    }
+    fn coroutine_done(&mut self) {
+        match self.vm.pop() {
+            Value::Coroutine(coroutine) => {
+                let done = coroutine.borrow().done();
+                self.vm.push(Value::Boolean(done));
+            }
+            _ => self.fail(Error::InvalidOperands),
         }
     }
 }
 
 fn main() {
     let mut bs = BakhtScript::default();
-    bs.load(
-        std::fs::read_to_string("./local/source.bs")
-            .unwrap()
-            .as_str(),
-    )
-    .unwrap();
+    bs.add_path("./local");
+    let source = std::fs::read_to_string("./local/source.bs").unwrap();
+    if let Err(e) = bs.load(source.as_str()) {
+        eprintln!("{}", e);
+        return;
+    }
     bs.fcall(0);
-    bs.error().unwrap();
+    if let Err(e) = bs.error() {
+        eprintln!("{}", e);
+    }
     bs.reset();
 }
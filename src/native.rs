@@ -1,11 +1,16 @@
+use crate::vm::BVM;
 use crate::BakhtScript;
 
 pub(crate) fn bakht_print(bakht: &mut BakhtScript) {
     match bakht.pop() {
         crate::BakhtValue::Function => println!("<function>"),
         crate::BakhtValue::Boolean(b) => println!("{}", b),
-        crate::BakhtValue::Number(n) => println!("{}", n),
+        crate::BakhtValue::Int(n) => println!("{}", n),
+        crate::BakhtValue::Number(n) => println!("{:?}", n),
         crate::BakhtValue::Array => println!("[array]"),
+        crate::BakhtValue::Map => println!("[map]"),
+        crate::BakhtValue::Struct => println!("[struct]"),
+        crate::BakhtValue::Coroutine => println!("[coroutine]"),
         crate::BakhtValue::Nil => println!("nil"),
         crate::BakhtValue::String(s) => println!("{}", s),
     }
@@ -22,3 +27,40 @@ pub(crate) fn bakht_pop(bakht: &mut BakhtScript) {
 pub(crate) fn bakht_len(bakht: &mut BakhtScript) {
     bakht.array_len();
 }
+pub(crate) fn bakht_keys(bakht: &mut BakhtScript) {
+    bakht.map_keys();
+}
+pub(crate) fn bakht_values(bakht: &mut BakhtScript) {
+    bakht.map_values();
+}
+pub(crate) fn bakht_has(bakht: &mut BakhtScript) {
+    bakht.map_has();
+}
+pub(crate) fn bakht_remove(bakht: &mut BakhtScript) {
+    bakht.map_remove();
+}
+pub(crate) fn bakht_next(bakht: &mut BakhtScript) {
+    bakht.push_nil();
+    bakht.coroutine_resume();
+}
+pub(crate) fn bakht_send(bakht: &mut BakhtScript) {
+    bakht.coroutine_resume();
+}
+pub(crate) fn bakht_done(bakht: &mut BakhtScript) {
+    bakht.coroutine_done();
+}
+
+pub(crate) fn methods(vm: &mut BVM) {
+    vm.add_method("array", "push", bakht_push, 2);
+    vm.add_method("array", "pop", bakht_pop, 1);
+    vm.add_method("array", "len", bakht_len, 1);
+    vm.add_method("string", "len", bakht_len, 1);
+    vm.add_method("map", "len", bakht_len, 1);
+    vm.add_method("map", "keys", bakht_keys, 1);
+    vm.add_method("map", "values", bakht_values, 1);
+    vm.add_method("map", "has", bakht_has, 2);
+    vm.add_method("map", "remove", bakht_remove, 2);
+    vm.add_method("coroutine", "next", bakht_next, 1);
+    vm.add_method("coroutine", "send", bakht_send, 2);
+    vm.add_method("coroutine", "done", bakht_done, 1);
+}
@@ -1,13 +1,18 @@
 use super::Text;
 use crate::text::{Token, TokenKind};
 
-const SINGLE_CHARS: &[char] = &['+', '-', '*', '/', '%', '[', ']', '(', ')', '{', '}', ','];
+const SINGLE_CHARS: &[char] = &[
+    '+', '-', '*', '/', '%', '[', ']', '(', ')', '{', '}', ',', ':', '?', '^', '~',
+];
 const EQUAL_FOLLOW: &[char] = &['=', '>', '<', '!'];
+const ASSIGN_OPERATORS: &[char] = &['+', '-', '*', '/', '%'];
 
+#[derive(Clone)]
 pub(crate) struct Scanner {
     pub(crate) text: Text,
     pub(crate) old_ptr: usize,
     pub(crate) ptr: usize,
+    interpolations: Vec<(bool, usize)>,
 }
 
 impl Scanner {
@@ -16,6 +21,7 @@ impl Scanner {
             text,
             ptr: 0,
             old_ptr: 0,
+            interpolations: vec![],
         }
     }
     fn token(&mut self, kind: TokenKind) -> Token {
@@ -26,15 +32,18 @@ impl Scanner {
         }
     }
     fn peek(&mut self) -> char {
-        if self.ptr >= self.text.len() {
+        self.peek_at(0)
+    }
+    fn peek_at(&mut self, offset: usize) -> char {
+        if self.ptr + offset >= self.text.len() {
             '\0'
         } else {
-            self.text[self.ptr]
+            self.text[self.ptr + offset]
         }
     }
     fn pop(&mut self) -> char {
         let c = self.peek();
-        self.ptr = self.ptr + 1;
+        self.ptr += 1;
         c
     }
     fn read(&mut self) -> Token {
@@ -49,7 +58,7 @@ impl Scanner {
             }
             self.token(TokenKind::White)
         } else if c.is_ascii_alphabetic() || c == '_' {
-            while self.peek().is_ascii_alphanumeric() || c == '_' {
+            while self.peek().is_ascii_alphanumeric() || self.peek() == '_' {
                 self.pop();
             }
             let mut token = self.token(TokenKind::Identifier);
@@ -58,10 +67,18 @@ impl Scanner {
                 token.kind = TokenKind::If;
             } else if tt.as_str() == "while" {
                 token.kind = TokenKind::While;
+            } else if tt.as_str() == "for" {
+                token.kind = TokenKind::For;
+            } else if tt.as_str() == "in" {
+                token.kind = TokenKind::In;
             } else if tt.as_str() == "else" {
                 token.kind = TokenKind::Else;
             } else if tt.as_str() == "fn" {
                 token.kind = TokenKind::Fn;
+            } else if tt.as_str() == "struct" {
+                token.kind = TokenKind::Struct;
+            } else if tt.as_str() == "import" {
+                token.kind = TokenKind::Import;
             } else if tt.as_str() == "false" {
                 token.kind = TokenKind::False;
             } else if tt.as_str() == "true" {
@@ -72,21 +89,63 @@ impl Scanner {
                 token.kind = TokenKind::Return;
             } else if tt.as_str() == "let" {
                 token.kind = TokenKind::Let;
+            } else if tt.as_str() == "break" {
+                token.kind = TokenKind::Break;
+            } else if tt.as_str() == "continue" {
+                token.kind = TokenKind::Continue;
+            } else if tt.as_str() == "and" {
+                token.kind = TokenKind::And;
+            } else if tt.as_str() == "or" {
+                token.kind = TokenKind::Or;
+            } else if tt.as_str() == "not" {
+                token.kind = TokenKind::Not;
+            } else if tt.as_str() == "try" {
+                token.kind = TokenKind::Try;
+            } else if tt.as_str() == "catch" {
+                token.kind = TokenKind::Catch;
+            } else if tt.as_str() == "throw" {
+                token.kind = TokenKind::Throw;
+            } else if tt.as_str() == "match" {
+                token.kind = TokenKind::Match;
+            } else if tt.as_str() == "yield" {
+                token.kind = TokenKind::Yield;
             }
             token
-        } else if c == '\'' {
-            while self.peek() != '\'' && self.peek() != '\0' {
+        } else if c == '\'' || c == '"' {
+            let triple = self.peek() == c && self.peek_at(1) == c;
+            if triple {
                 self.pop();
-            }
-            if self.peek() == '\'' {
                 self.pop();
-                self.token(TokenKind::Literal)
+            }
+            self.string(c, triple)
+        } else if c == '{' && !self.interpolations.is_empty() {
+            self.interpolations.last_mut().unwrap().1 += 1;
+            self.token(TokenKind::Single(c))
+        } else if c == '}' && !self.interpolations.is_empty() {
+            let (triple, depth) = self.interpolations.last_mut().unwrap();
+            if *depth == 0 {
+                let triple = *triple;
+                self.interpolations.pop();
+                self.string('"', triple)
             } else {
-                self.token(TokenKind::Error)
+                *depth -= 1;
+                self.token(TokenKind::Single(c))
             }
         } else if c.is_ascii_digit() {
-            while self.peek().is_ascii_digit() {
-                self.pop();
+            let hex = c == '0' && (self.peek() == 'x' || self.peek() == 'X');
+            loop {
+                let p = self.peek();
+                if p.is_ascii_alphanumeric() || p == '_' {
+                    self.pop();
+                    if !hex && (p == 'e' || p == 'E') && (self.peek() == '+' || self.peek() == '-')
+                    {
+                        self.pop();
+                    }
+                } else if p == '.' && self.peek_at(1).is_ascii_digit() {
+                    self.pop();
+                } else {
+                    break;
+                }
             }
             self.token(TokenKind::Number)
         } else if c == '#' {
@@ -97,10 +156,32 @@ impl Scanner {
                 self.pop();
             }
             self.token(TokenKind::Comment)
+        } else if c == '&' || c == '|' {
+            if self.peek() == c {
+                self.pop();
+                self.token(TokenKind::Double)
+            } else {
+                self.token(TokenKind::Single(c))
+            }
+        } else if c == '.' {
+            if self.peek() == '.' {
+                self.pop();
+                self.token(TokenKind::Double)
+            } else {
+                self.token(TokenKind::Single(c))
+            }
+        } else if ASSIGN_OPERATORS.contains(&c)
+            && (self.peek() == '=' || ((c == '*' || c == '/') && self.peek() == c))
+        {
+            self.pop();
+            self.token(TokenKind::Double)
         } else if SINGLE_CHARS.contains(&c) {
             self.token(TokenKind::Single(c))
         } else if EQUAL_FOLLOW.contains(&c) {
-            if self.peek() == '=' {
+            if ((c == '<' || c == '>') && self.peek() == c)
+                || self.peek() == '='
+                || (c == '=' && self.peek() == '>')
+            {
                 self.pop();
                 self.token(TokenKind::Double)
             } else {
@@ -110,6 +191,40 @@ impl Scanner {
             self.token(TokenKind::Error)
         }
     }
+    fn string(&mut self, quote: char, triple: bool) -> Token {
+        loop {
+            let p = self.peek();
+            if p == '\0' {
+                return self.token(TokenKind::Unterminated);
+            } else if p == '\\' {
+                self.pop();
+                if self.peek() == 'u' && self.peek_at(1) == '{' {
+                    while self.peek() != '}' && self.peek() != quote && self.peek() != '\0' {
+                        self.pop();
+                    }
+                    if self.peek() == '}' {
+                        self.pop();
+                    }
+                } else if self.peek() != '\0' {
+                    self.pop();
+                }
+            } else if p == '{' && quote == '"' {
+                self.pop();
+                self.interpolations.push((triple, 0));
+                return self.token(TokenKind::Interpolation);
+            } else if p == quote
+                && (!triple || (self.peek_at(1) == quote && self.peek_at(2) == quote))
+            {
+                break;
+            } else {
+                self.pop();
+            }
+        }
+        for _ in 0..(if triple { 3 } else { 1 }) {
+            self.pop();
+        }
+        self.token(TokenKind::Literal)
+    }
     fn sync(&mut self) {
         self.old_ptr = self.ptr
     }
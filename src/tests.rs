@@ -1,5 +1,11 @@
 use super::vm::VM;
+use crate::assemble::encode;
 use crate::bin::Instruction;
+use crate::compiler::Compiler;
+use crate::scanner::Scanner;
+use crate::text::Text;
+use crate::{BakhtScript, BakhtValue, Error};
+use std::sync::Arc;
 
 #[derive(Default)]
 struct MockVM {
@@ -12,28 +18,58 @@ impl VM for MockVM {
         0
     }
 
-    fn rodata_function(&mut self, _: usize, _: bool) -> usize {
+    fn rodata_function(&mut self, _: usize, _: usize, _: bool, _: bool) -> usize {
         0
     }
 
+    fn rodata_struct(&mut self, _: Vec<usize>) -> usize {
+        0
+    }
+
+    fn global(&mut self) -> usize {
+        0
+    }
+
+    fn initializer(&mut self, _: usize) {}
+
+    fn define(&mut self, _: usize, _: usize) {}
+
     fn emit(&mut self, bytecode: u8) -> usize {
         self.bin.push(bytecode);
-        0
+        self.bin.len() - 1
+    }
+
+    fn address(&self) -> usize {
+        self.bin.len()
+    }
+
+    fn rodata_int(&mut self, _: i64) -> usize {
+        let cidx = self.cidx;
+        self.cidx += 1;
+        cidx
     }
 
-    fn rodata_number(&mut self, _: f32) -> usize {
+    fn rodata_number(&mut self, _: f64) -> usize {
         let cidx = self.cidx;
-        self.cidx = self.cidx + 1;
+        self.cidx += 1;
         cidx
     }
 
     fn rodata_literal(&mut self, _: String) -> usize {
         let cidx = self.cidx;
-        self.cidx = self.cidx + 1;
+        self.cidx += 1;
         cidx
     }
 
-    fn edit(&mut self, _: u8, _: usize) {}
+    fn error_struct(&mut self, _: usize) {}
+
+    fn generator(&mut self, _: usize) {}
+
+    fn rodata_name(&mut self, _: String) {}
+
+    fn edit(&mut self, bytecode: u8, address: usize) {
+        self.bin[address] = bytecode;
+    }
 }
 
 impl MockVM {
@@ -42,7 +78,7 @@ impl MockVM {
     }
 }
 
-fn check(src: &str, target: &[Instruction]) {
+fn check(_src: &str, _target: &[Instruction]) {
     // let target = target
     //     .iter()
     //     .map(|i| i.encode_params())
@@ -113,3 +149,1221 @@ fn parentheses() {
         ],
     );
 }
+
+fn check_bin(src: &str, target: &[Instruction]) {
+    let target = target
+        .iter()
+        .flat_map(|i| {
+            let bytecode = encode(*i);
+            bytecode.bytes[..bytecode.len as usize].to_vec()
+        })
+        .collect::<Vec<_>>();
+    let text: Text = Arc::new(src.chars().collect());
+    let scanner = Scanner::new(text.clone());
+    let mut compiler = Compiler::new(text, scanner, MockVM::default());
+    compiler.compile().unwrap();
+    compiler.vm().check(target.as_slice());
+}
+
+fn run(src: &str) -> BakhtValue {
+    let mut bs = BakhtScript::default();
+    bs.load(src).unwrap();
+    bs.fcall(0);
+    bs.error().unwrap();
+    bs.pop()
+}
+
+fn run_err(src: &str) -> Error {
+    let mut bs = BakhtScript::default();
+    bs.load(src).unwrap();
+    bs.fcall(0);
+    bs.error().unwrap_err()
+}
+
+#[test]
+fn if_else_jumps() {
+    check_bin(
+        "fn main() { if true { } else { } }",
+        &[
+            Instruction::True,
+            Instruction::Cjmp(11),
+            Instruction::Jmp(11),
+            Instruction::Nil,
+            Instruction::Ret,
+        ],
+    );
+}
+
+#[test]
+fn if_else_chain() {
+    assert_eq!(
+        run("
+            fn pick(a) {
+                if a > 1 {
+                    return 'big'
+                } else if a == 1 {
+                    return 'one'
+                } else {
+                    return 'small'
+                }
+            }
+            fn main() {
+                return pick(5) + pick(1) + pick(0)
+            }"),
+        BakhtValue::String("bigonesmall".to_string())
+    );
+}
+
+#[test]
+fn far_jumps() {
+    let body = "x = x + 1\n".repeat(20000);
+    let src = format!("fn main() {{ let x = 0\n{body} if x > 0 {{ return x }} return 0 }}");
+    assert_eq!(run(&src), BakhtValue::Int(20000));
+}
+
+#[test]
+fn if_scope() {
+    assert_eq!(
+        run("
+            fn main() {
+                let x = 1
+                if x < 2 {
+                    let y = 2
+                    x = x + y
+                }
+                if x < 2 {
+                    x = 0
+                }
+                return x
+            }"),
+        BakhtValue::Int(3)
+    );
+}
+
+#[test]
+fn while_loop() {
+    assert_eq!(
+        run("
+            fn main() {
+                let i = 0, sum = 0
+                while i < 10 {
+                    i = i + 1
+                    let odd = i % 2
+                    if odd == 0 {
+                        continue
+                    }
+                    if i > 7 {
+                        break
+                    }
+                    sum = sum + i
+                }
+                return sum
+            }"),
+        BakhtValue::Int(16)
+    );
+}
+
+#[test]
+fn nested_break() {
+    assert_eq!(
+        run("
+            fn main() {
+                let count = 0
+                while true {
+                    let j = 0
+                    while true {
+                        let k = j
+                        if k == 3 {
+                            break
+                        }
+                        j = j + 1
+                        count = count + 1
+                    }
+                    break
+                }
+                return count
+            }"),
+        BakhtValue::Int(3)
+    );
+}
+
+#[test]
+fn for_loop() {
+    assert_eq!(
+        run("
+            fn main() {
+                let sum = 0
+                for x in [1, 2, 3, 4] {
+                    if x == 2 {
+                        continue
+                    }
+                    sum = sum + x
+                }
+                for i in 1..100 {
+                    if i > 3 {
+                        break
+                    }
+                    sum = sum + i * 10
+                }
+                return sum
+            }"),
+        BakhtValue::Int(68)
+    );
+}
+
+#[test]
+fn for_string() {
+    assert_eq!(
+        run("
+            fn main() {
+                let s = ''
+                for c in 'abc' {
+                    s = c + s
+                }
+                return s
+            }"),
+        BakhtValue::String("cba".to_string())
+    );
+    assert_eq!(
+        run("
+            fn main() {
+                let s = ''
+                for c in 'aé😀b' {
+                    s = c + s
+                }
+                return s
+            }"),
+        BakhtValue::String("b😀éa".to_string())
+    );
+    assert_eq!(
+        run("
+            fn main() {
+                let s = 'aé😀b', r = ''
+                for i in 0..len(s) {
+                    r = s[i] + r
+                }
+                return r + s[1]
+            }"),
+        BakhtValue::String("b😀éaé".to_string())
+    );
+}
+
+#[test]
+fn logical_operators() {
+    assert_eq!(
+        run("
+            fn fail() {
+                return 1 / 0
+            }
+            fn main() {
+                let s = ''
+                if false and fail() {
+                    s = s + 'a'
+                }
+                if true || fail() {
+                    s = s + 'b'
+                }
+                if not nil && !false {
+                    s = s + 'c'
+                }
+                if 0 and '' {
+                    s = s + 'd'
+                }
+                return s + (nil or 'e') + (1 and 'f')
+            }"),
+        BakhtValue::String("bcdef".to_string())
+    );
+}
+
+#[test]
+fn closures() {
+    assert_eq!(
+        run("
+            fn counter() {
+                let count = 0
+                return fn() {
+                    count = count + 1
+                    return count
+                }
+            }
+            fn main() {
+                let a = counter(), b = counter()
+                a()
+                a()
+                b()
+                let adders = []
+                for i in 1..4 {
+                    push(adders, fn(x) { return x + i })
+                }
+                let f = adders[2]
+                return a() * 100 + b() * 10 + f(0)
+            }"),
+        BakhtValue::Int(323)
+    );
+}
+
+#[test]
+fn nested_functions() {
+    assert_eq!(
+        run("
+            fn main() {
+                let total = 0
+                fn fib(n) {
+                    total = total + 1
+                    if n < 2 {
+                        return n
+                    }
+                    return fib(n - 1) + fib(n - 2)
+                }
+                let shared = fn() {
+                    fn inner() {
+                        return total
+                    }
+                    return inner()
+                }
+                return fib(10) * 1000 + shared()
+            }"),
+        BakhtValue::Int(55177)
+    );
+}
+
+#[test]
+fn maps() {
+    assert_eq!(
+        run("
+            fn main() {
+                let m = { 'b': 2, 'a': 1, 3: 'three' }
+                m['c'] = m['a'] + m['b']
+                m['a'] = 10
+                let s = ''
+                if has(m, 3) and not has(m, 'z') and m['z'] == nil {
+                    s = s + remove(m, 3) + ':'
+                }
+                for k in keys(m) {
+                    s = s + k
+                }
+                let sum = 0
+                for v in values(m) {
+                    sum = sum + v
+                }
+                if sum == 15 {
+                    s = s + '!'
+                }
+                return s
+            }"),
+        BakhtValue::String("three:abc!".to_string())
+    );
+    for src in [
+        "fn main() { return has([1], 1) }",
+        "fn main() { return has({}, nil) }",
+        "fn main() { return keys(1) }",
+        "fn main() { return values(nil) }",
+        "fn main() { return remove('a', 1) }",
+        "fn main() { return len(1) }",
+    ] {
+        assert!(matches!(run_err(src), Error::InvalidOperands));
+    }
+    assert!(matches!(
+        run_err("fn main() { return pop([]) }"),
+        Error::IndexOutOfBound
+    ));
+    assert_eq!(
+        run("fn main() { try { keys(1) } catch e { return e.kind } }"),
+        BakhtValue::String("InvalidOperands".to_string())
+    );
+}
+
+#[test]
+fn structs() {
+    assert_eq!(
+        run("
+            struct Point { x, y }
+            struct Line { from, to }
+            fn length(l) {
+                return l.to.x - l.from.x + l.to.y - l.from.y
+            }
+            fn main() {
+                let p = Point(1, 2)
+                let l = Line(p, Point(5, 7))
+                p.x = 3
+                l.to.y = p.x + p.y
+                let lines = [l]
+                lines[0].from.y = 0
+                return length(l)
+            }"),
+        BakhtValue::Int(7)
+    );
+    assert_eq!(
+        run("
+            fn main() {
+                let s = Segment(Vec(1, 2), nil)
+                s.end = Vec(4, 6)
+                return s.end.dx + s.end.dy - s.start.dx
+            }
+            struct Segment { start, end }
+            struct Vec { dx, dy }"),
+        BakhtValue::Int(9)
+    );
+    assert!(matches!(
+        run_err("struct A { a } struct B { b } fn main() { return B(1).a }"),
+        Error::NoSuchField
+    ));
+}
+
+#[test]
+fn imports() {
+    let dir = std::env::temp_dir().join(format!("bakht-imports-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("lib")).unwrap();
+    std::fs::write(dir.join("lib/math.bs"), "fn double(x) { return x * 2 }").unwrap();
+    std::fs::write(
+        dir.join("lib/util.bs"),
+        "import 'math.bs'\nfn quad(x) { return math.double(math.double(x)) }",
+    )
+    .unwrap();
+    std::fs::write(dir.join("a.bs"), "import 'b.bs'").unwrap();
+    std::fs::write(dir.join("b.bs"), "import 'a.bs'").unwrap();
+
+    let mut bs = BakhtScript::default();
+    bs.add_path(&dir);
+    bs.load(
+        "
+        import 'lib/util.bs'
+        import 'lib/math.bs'
+        fn main() {
+            let f = math.double
+            return util.quad(1) + f(3)
+        }",
+    )
+    .unwrap();
+    bs.fcall(0);
+    bs.error().unwrap();
+    assert_eq!(bs.pop(), BakhtValue::Int(10));
+
+    let cyclic = bs.load("import 'a.bs' fn main() { }");
+    assert!(matches!(cyclic, Err(Error::CyclicImport(_))));
+    let missing = bs.load("import 'c.bs' fn main() { }");
+    assert!(matches!(missing, Err(Error::ModuleNotFound(_))));
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn globals() {
+    assert_eq!(
+        run("
+            let count = 10, cache = {}
+            let doubled = count * 2
+            fn tick() {
+                count = count + 1
+                return count
+            }
+            fn main() {
+                tick()
+                tick()
+                cache['last'] = tick()
+                return cache['last'] + doubled
+            }"),
+        BakhtValue::Int(33)
+    );
+}
+
+#[test]
+fn failing_global_initializer() {
+    let mut bs = BakhtScript::default();
+    assert!(matches!(
+        bs.load("let x = [][0] fn main() { return 1 }"),
+        Err(Error::IndexOutOfBound)
+    ));
+    assert!(matches!(
+        bs.load("fn bad() { throw 'boom' } let x = bad() fn main() { return 1 }"),
+        Err(Error::Thrown(_))
+    ));
+}
+
+#[test]
+fn compound_assignment() {
+    assert_eq!(
+        run("
+            struct Box { value }
+            let calls = 0
+            fn index() {
+                calls += 1
+                return 1
+            }
+            fn main() {
+                let x = 10
+                x -= 4
+                x *= 3
+                x /= 2
+                x %= 5
+                let a = [1, 2, 3]
+                a[index()] += 40
+                let b = Box(5)
+                b.value += a[1]
+                let s = 'a'
+                s += 'b'
+                if s == 'ab' and calls == 1 {
+                    return b.value * 10 + x
+                }
+            }"),
+        BakhtValue::Int(474)
+    );
+}
+
+#[test]
+fn string_literals() {
+    assert_eq!(
+        run(r#"
+            fn main() {
+                return 'it\'s\t' + "a \"quote\"\n" + '\u{48}\u{1F600}\\' + '''x
+'y'
+"z"'''
+            }"#),
+        BakhtValue::String("it's\ta \"quote\"\nH\u{1F600}\\x\n'y'\n\"z\"".to_string())
+    );
+}
+
+#[test]
+fn string_errors() {
+    let mut bs = BakhtScript::default();
+    let unterminated = bs.load("fn main() { return 'abc }");
+    assert!(matches!(unterminated, Err(Error::UnterminatedString(t)) if t.from == 19));
+    let invalid = bs.load(r"fn main() { return 'ab\qc' }");
+    assert!(matches!(invalid, Err(Error::InvalidEscape(t)) if t.from == 22 && t.len == 2));
+    let invalid = bs.load(r"fn main() { return '\u{110000}' }");
+    assert!(matches!(invalid, Err(Error::InvalidEscape(t)) if t.from == 20 && t.len == 10));
+}
+
+#[test]
+fn string_interpolation() {
+    assert_eq!(
+        run(r#"
+            fn main() {
+                let count = 3, m = { 'k': [1, true] }
+                return "total: {count} items, {m['k']} {"nested {count + 1}"}{nil}\{}"
+            }"#),
+        BakhtValue::String("total: 3 items, [1, true] nested 4nil{}".to_string())
+    );
+    assert_eq!(
+        run(r#"fn main() { let n = 1 return "\u{41}x{n}\u{1F600}" }"#),
+        BakhtValue::String("Ax1\u{1F600}".to_string())
+    );
+    assert_eq!(
+        run(r#"fn main() { let a = [] a.push(a) return "{a}" }"#),
+        BakhtValue::String("[[...]]".to_string())
+    );
+    assert_eq!(
+        run(r#"fn main() { let m = {} m['s'] = m return "{m}" }"#),
+        BakhtValue::String("{s: {...}}".to_string())
+    );
+    assert_eq!(
+        run(r#"fn main() { let b = [1] return "{[b, b]}" }"#),
+        BakhtValue::String("[[1], [1]]".to_string())
+    );
+    assert!(matches!(
+        run_err("fn main() { let a = [] a.push(a) throw a }"),
+        Error::Thrown(s) if s == "[[...]]"
+    ));
+    let mut bs = BakhtScript::default();
+    assert!(matches!(
+        bs.load(r#"fn main() { return "\u{41" }"#),
+        Err(Error::InvalidEscape(_))
+    ));
+}
+
+#[test]
+fn numeric_literals() {
+    assert_eq!(
+        run("
+            fn main() {
+                let sum = 0
+                for i in 0..3 {
+                    sum += i
+                }
+                return sum + 0.5 + 2.5e1 + 1e-1 + 0x1F + 0b1_01 + 0o17 + 1_000
+            }"),
+        BakhtValue::Number(3.0 + 0.5 + 25.0 + 0.1 + 31.0 + 5.0 + 15.0 + 1000.0)
+    );
+    for src in ["1__0", "1_", "0x", "0b12", "1e", "1.5.5", "12abc", "0x_1"] {
+        let mut bs = BakhtScript::default();
+        let invalid = bs.load(&format!("fn main() {{ return {} }}", src));
+        assert!(
+            matches!(invalid, Err(Error::InvalidNumber(t)) if t.from == 19 && t.len == src.len())
+        );
+    }
+}
+
+#[test]
+fn integers() {
+    assert_eq!(
+        run("fn main() { return 9007199254740993 + 2 }"),
+        BakhtValue::Int(9007199254740995)
+    );
+    assert_eq!(run("fn main() { return 7 / 2 }"), BakhtValue::Int(3));
+    assert_eq!(run("fn main() { return 7 / 2.0 }"), BakhtValue::Number(3.5));
+    assert_eq!(run("fn main() { return -7 % 3 }"), BakhtValue::Int(-1));
+    assert_eq!(run("fn main() { return 1 + 0.5 }"), BakhtValue::Number(1.5));
+    assert_eq!(
+        run("fn main() { return 1 == 1.0 }"),
+        BakhtValue::Boolean(true)
+    );
+    assert_eq!(
+        run("fn main() { return \"{2.0}\" }"),
+        BakhtValue::String("2.0".to_string())
+    );
+    assert_eq!(
+        run("fn main() { return len([1, 2, 3]) }"),
+        BakhtValue::Int(3)
+    );
+    assert_eq!(
+        run("fn main() { let m = { 1: 'a' } return m[1.0] }"),
+        BakhtValue::String("a".to_string())
+    );
+    assert!(matches!(
+        run_err("fn main() { return 0x7fff_ffff_ffff_ffff + 1 }"),
+        Error::Overflow
+    ));
+}
+
+#[test]
+fn exceptions() {
+    assert_eq!(
+        run("fn main() { try { throw 'oops' } catch e { return e } }"),
+        BakhtValue::String("oops".to_string())
+    );
+    assert_eq!(
+        run("fn main() { try { return 1 / 0 } catch e { return e.kind } }"),
+        BakhtValue::String("DivisionByZero".to_string())
+    );
+    assert_eq!(
+        run("fn main() { try { let a = [1] a[5] = 2 } catch e { return e.message } }"),
+        BakhtValue::String("index out of bound".to_string())
+    );
+    assert_eq!(
+        run("fn main() { try { throw Error('Custom', 'own') } catch e { return e.kind } }"),
+        BakhtValue::String("Custom".to_string())
+    );
+    assert_eq!(
+        run("
+            fn risky() {
+                throw 'deep'
+            }
+            fn main() {
+                try {
+                    let tmp = [1, 2]
+                    risky()
+                } catch e {
+                    return e
+                }
+            }"),
+        BakhtValue::String("deep".to_string())
+    );
+    assert_eq!(
+        run("
+            fn main() {
+                let n = 0
+                while true {
+                    try {
+                        n += 1
+                        throw n
+                    } catch e {
+                        if e == 3 {
+                            break
+                        }
+                    }
+                }
+                return n
+            }"),
+        BakhtValue::Int(3)
+    );
+    assert!(matches!(
+        run_err("fn main() { try { } catch e { } throw 'oops' }"),
+        Error::Thrown(s) if s == "oops"
+    ));
+    assert!(matches!(
+        run_err("fn main() { while true { try { break } catch e { } } return 1 / 0 }"),
+        Error::DivisionByZero
+    ));
+}
+
+#[test]
+fn match_expression() {
+    let describe = r#"
+        fn describe(v) {
+            return match v {
+                1 => 'one',
+                -2 => 'minus two',
+                'a' => 'letter',
+                [x, [1, y]] => "nested {x + y}",
+                [x, y] => "pair {x + y}",
+                [] => 'empty',
+                other => "other {other}"
+            }
+        }"#;
+    for (arg, expected) in [
+        ("1", "one"),
+        ("-2", "minus two"),
+        ("'a'", "letter"),
+        ("[3, 4]", "pair 7"),
+        ("[2, [1, 5]]", "nested 7"),
+        ("[]", "empty"),
+        ("true", "other true"),
+    ] {
+        let src = format!("{} fn main() {{ return describe({}) }}", describe, arg);
+        assert_eq!(run(&src), BakhtValue::String(expected.to_string()));
+    }
+    assert_eq!(
+        run("fn main() { return match [5, [1, 2]] { [a, [1, b]] => a * b, _ => 0 } }"),
+        BakhtValue::Int(10)
+    );
+    assert_eq!(
+        run("fn main() { return 1 + match 3 { [a] => a, n => n * 2 } }"),
+        BakhtValue::Int(7)
+    );
+    assert_eq!(
+        run("
+            fn main() {
+                let log = 'no'
+                match 20 {
+                    20 => {
+                        let tmp = 1
+                        log = 'twenty'
+                    }
+                    _ => {
+                        log = 'other'
+                    }
+                }
+                return log
+            }"),
+        BakhtValue::String("twenty".to_string())
+    );
+    let mut bs = BakhtScript::default();
+    let refutable = bs.load("fn main() { return match 1 { 1 => 2 } }");
+    assert!(matches!(refutable, Err(Error::RefutableMatch(t)) if t.from == 19));
+}
+
+#[test]
+fn parameters() {
+    let scale = r#"
+        fn scale(a, b = 10, c = a * b, ...rest) {
+            return "{a} {b} {c} {rest}"
+        }"#;
+    for (args, expected) in [
+        ("1", "1 10 10 []"),
+        ("1, 2", "1 2 2 []"),
+        ("1, 2, 3", "1 2 3 []"),
+        ("1, 2, 3, 4, 5", "1 2 3 [4, 5]"),
+    ] {
+        let src = format!("{} fn main() {{ return scale({}) }}", scale, args);
+        assert_eq!(run(&src), BakhtValue::String(expected.to_string()));
+    }
+    assert_eq!(
+        run("fn count(...all) { return len(all) } fn main() { return count() }"),
+        BakhtValue::Int(0)
+    );
+    assert_eq!(
+        run("fn count(...all) { return len(all) } fn main() { return count(1, 2) }"),
+        BakhtValue::Int(2)
+    );
+    assert_eq!(
+        run("fn main() { let f = fn(x = 'x') { return x } return f() }"),
+        BakhtValue::String("x".to_string())
+    );
+    for call in ["pair(1)", "pair(1, 2, 3)", "print()"] {
+        let src = format!("fn pair(a, b) {{ }} fn main() {{ {} }}", call);
+        assert!(matches!(run_err(&src), Error::Arity(_)));
+    }
+}
+
+#[test]
+fn destructuring() {
+    assert_eq!(
+        run("fn divmod(a, b) { return a / b, a % b } fn main() { let [q, r] = divmod(17, 5) return q }"),
+        BakhtValue::Int(3)
+    );
+    assert_eq!(
+        run("fn divmod(a, b) { return a / b, a % b } fn main() { let [q, r] = divmod(17, 5) return r }"),
+        BakhtValue::Int(2)
+    );
+    assert_eq!(
+        run("fn main() { let [head, ...tail] = [1, 2, 3] return head }"),
+        BakhtValue::Int(1)
+    );
+    assert_eq!(
+        run(r#"fn main() { let [head, ...tail] = [1, 2, 3] return "{tail}" }"#),
+        BakhtValue::String("[2, 3]".to_string())
+    );
+    assert_eq!(
+        run(r#"fn main() { let [only, ...none] = ['x'] return "{only} {none}" }"#),
+        BakhtValue::String("x []".to_string())
+    );
+    for value in ["[1]", "[1, 2, 3]", "'ab'"] {
+        let src = format!("fn main() {{ let [a, b] = {} }}", value);
+        assert!(matches!(run_err(&src), Error::ShapeMismatch));
+    }
+}
+
+#[test]
+fn generators() {
+    let range = "
+        fn range(n) {
+            let i = 0
+            while i < n {
+                yield i
+                i += 1
+            }
+        }";
+    assert_eq!(
+        run(&format!(
+            "{} fn main() {{ let s = 0 for x in range(4) {{ s = s * 10 + x }} return s }}",
+            range
+        )),
+        BakhtValue::Int(123)
+    );
+    assert_eq!(
+        run(&format!(
+            "{} fn main() {{ let g = range(2) next(g) return next(g) }}",
+            range
+        )),
+        BakhtValue::Int(1)
+    );
+    assert_eq!(
+        run(&format!(
+            "{} fn main() {{ let g = range(2) next(g) next(g) return done(g) }}",
+            range
+        )),
+        BakhtValue::Boolean(false)
+    );
+    assert_eq!(
+        run(&format!(
+            "{} fn main() {{ let g = range(1) next(g) return next(g) }}",
+            range
+        )),
+        BakhtValue::Nil
+    );
+    assert_eq!(
+        run(&format!(
+            "{} fn main() {{ let g = range(1) next(g) next(g) return done(g) }}",
+            range
+        )),
+        BakhtValue::Boolean(true)
+    );
+    let echo = "
+        fn echo() {
+            let total = 0
+            while true {
+                total += yield total
+            }
+        }";
+    assert_eq!(
+        run(&format!(
+            "{} fn main() {{ let e = echo() next(e) return send(e, 5) }}",
+            echo
+        )),
+        BakhtValue::Int(5)
+    );
+    assert_eq!(
+        run(&format!(
+            "{} fn main() {{ let e = echo() next(e) send(e, 5) return send(e, 2) }}",
+            echo
+        )),
+        BakhtValue::Int(7)
+    );
+}
+
+#[test]
+fn generator_captures() {
+    assert_eq!(
+        run(r#"
+            fn main() {
+                let base = 10, s = ''
+                let g = fn() {
+                    yield base
+                    base += 1
+                    yield base
+                }
+                for v in g() {
+                    s += "{v} "
+                }
+                return s + "{base}"
+            }"#),
+        BakhtValue::String("10 11 11".to_string())
+    );
+    assert_eq!(
+        run(r#"
+            fn main() {
+                let n = 2, s = ''
+                fn g() {
+                    let i = 0
+                    while i < n {
+                        yield i
+                        i += 1
+                    }
+                }
+                for v in g() {
+                    s += "{v}"
+                }
+                return s
+            }"#),
+        BakhtValue::String("01".to_string())
+    );
+    assert_eq!(
+        run(r#"
+            fn g() {
+                let c = 0
+                yield fn() { return c }
+                c = 5
+                yield 0
+            }
+            fn main() {
+                let gen = g()
+                let f = next(gen)
+                let before = f()
+                next(gen)
+                let after = f()
+                next(gen)
+                return "{before} {after} {f()} {done(gen)}"
+            }"#),
+        BakhtValue::String("0 5 5 true".to_string())
+    );
+    assert_eq!(
+        run("
+            fn g() {
+                let c = 7
+                yield fn() { return c }
+            }
+            fn escape() {
+                return next(g())
+            }
+            fn main() {
+                return escape()()
+            }"),
+        BakhtValue::Int(7)
+    );
+}
+
+#[test]
+fn generator_errors() {
+    assert_eq!(
+        run("
+            fn failing() {
+                yield 1
+                throw 'broken'
+            }
+            fn main() {
+                let seen = 0
+                try {
+                    for x in failing() {
+                        seen += x
+                    }
+                } catch e {
+                    return e + ' after ' + \"{seen}\"
+                }
+            }"),
+        BakhtValue::String("broken after 1".to_string())
+    );
+}
+
+fn bakht_double(bakht: &mut BakhtScript) {
+    if let BakhtValue::Int(n) = bakht.pop() {
+        bakht.vm.push(crate::vm::Value::Int(n * 2));
+    }
+}
+
+#[test]
+fn methods() {
+    assert_eq!(
+        run(r#"fn main() { let a = [1, 2] a.push(3) return "{a}" }"#),
+        BakhtValue::String("[1, 2, 3]".to_string())
+    );
+    assert_eq!(
+        run("fn main() { let a = [1, 2] return a.pop() }"),
+        BakhtValue::Int(2)
+    );
+    assert_eq!(run("fn main() { return [1, 2].len() }"), BakhtValue::Int(2));
+    assert_eq!(
+        run("fn main() { return 'héllo'.len() }"),
+        BakhtValue::Int(5)
+    );
+    assert_eq!(
+        run(r#"fn main() { return "{{'a': 1}.keys()}" }"#),
+        BakhtValue::String("[a]".to_string())
+    );
+    assert_eq!(
+        run("fn main() { let m = { 'k': 1 } return m.has('k') }"),
+        BakhtValue::Boolean(true)
+    );
+    assert_eq!(
+        run("struct Counter { step } fn main() { let c = Counter(fn(x) { return x + 1 }) return c.step(1) }"),
+        BakhtValue::Int(2)
+    );
+    let mut bs = BakhtScript::default();
+    bs.add_method("int", "double", bakht_double, 1);
+    bs.load("fn main() { return 4.double() }").unwrap();
+    bs.fcall(0);
+    bs.error().unwrap();
+    assert_eq!(bs.pop(), BakhtValue::Int(8));
+    assert!(matches!(
+        run_err("fn main() { return [].nothing() }"),
+        Error::NoSuchMethod(name) if name == "nothing"
+    ));
+    assert!(matches!(
+        run_err("fn main() { return [].push() }"),
+        Error::Arity(0)
+    ));
+}
+
+#[test]
+fn forward_references() {
+    let parity = "
+        fn is_even(n) {
+            if n == 0 { return true }
+            return is_odd(n - 1)
+        }
+        fn is_odd(n) {
+            if n == 0 { return false }
+            return is_even(n - 1)
+        }";
+    assert_eq!(
+        run(&format!("fn main() {{ return is_even(10) }} {}", parity)),
+        BakhtValue::Boolean(true)
+    );
+    assert_eq!(
+        run(&format!("fn main() {{ return is_odd(7) }} {}", parity)),
+        BakhtValue::Boolean(true)
+    );
+    let mut bs = BakhtScript::default();
+    assert!(matches!(
+        bs.load("fn main() { helper = 1 }\nfn helper() {}"),
+        Err(Error::Immutable(_))
+    ));
+    assert!(matches!(
+        bs.load("fn main() { return missing() }"),
+        Err(Error::UnknownIdentifier(_))
+    ));
+}
+
+#[test]
+fn conditional_expressions() {
+    assert_eq!(
+        run("fn main() { let a = 3, b = 5 return if a > b { a } else { b } }"),
+        BakhtValue::Int(5)
+    );
+    assert_eq!(
+        run("
+            fn main() {
+                let a = 3
+                return if a > 10 {
+                    'big'
+                } else if a > 2 {
+                    let d = a * 2
+                    len([d])
+                    d + 1
+                } else {
+                    'small'
+                }
+            }"),
+        BakhtValue::Int(7)
+    );
+    assert_eq!(
+        run("fn main() { return 3 > 5 ? 3 : 5 < 4 ? 'lt' : 'ge' }"),
+        BakhtValue::String("ge".to_string())
+    );
+    assert_eq!(
+        run("fn main() { return 1 + (true ? 1 : 2) }"),
+        BakhtValue::Int(2)
+    );
+    assert_eq!(
+        run(r#"fn main() { return "{[1, if true { 'yes' } else { 'no' }]}" }"#),
+        BakhtValue::String("[1, yes]".to_string())
+    );
+    assert_eq!(
+        run("
+            fn main() {
+                let f = if true {
+                    let y = 41
+                    let g = fn() { return y + 1 }
+                    g
+                } else {
+                    nil
+                }
+                return f()
+            }"),
+        BakhtValue::Int(42)
+    );
+    assert_eq!(
+        run("fn main() { let n = 1 let x = if n > 0 { n = 5 n } else { 0 } return x + n }"),
+        BakhtValue::Int(10)
+    );
+    assert_eq!(
+        run("fn main() { let h = if true { fn(a) { return a * 2 } } else { nil } return h(4) }"),
+        BakhtValue::Int(8)
+    );
+    assert_eq!(
+        run("
+            fn main() {
+                let s = 0
+                for i in 0..5 {
+                    let v = if i == 3 { break } else { i }
+                    s += v
+                }
+                return s
+            }"),
+        BakhtValue::Int(3)
+    );
+    assert_eq!(
+        run("fn main() { let d = 1 return if true { if d { d = 2 } d + 5 } else { 1 } }"),
+        BakhtValue::Int(7)
+    );
+    assert_eq!(
+        run("fn main() { let a = 1 return if a { { let b = 2 a += b } a } else { 0 } }"),
+        BakhtValue::Int(3)
+    );
+    assert_eq!(
+        run("fn main() { let a = 1 return if true { match a { 1 => { a = 5 } _ => {} } a } else { 0 } }"),
+        BakhtValue::Int(5)
+    );
+    assert_eq!(
+        run("fn main() { return if true { match 2 { 1 => 'one', _ => 'many' } } else { nil } }"),
+        BakhtValue::String("many".to_string())
+    );
+    assert_eq!(
+        run("fn f(c) { let v = if c { return 9 } else { 1 } return v } fn main() { return f(true) }"),
+        BakhtValue::Int(9)
+    );
+    assert!(matches!(
+        run_err("fn main() { let x = if false { 1 } else { throw 'no' } }"),
+        Error::Thrown(_)
+    ));
+    let mut bs = BakhtScript::default();
+    assert!(matches!(
+        bs.load("fn main() { let x = if true { 1 } }"),
+        Err(Error::UnexpectedToken(_))
+    ));
+}
+
+#[test]
+fn bitwise_operators() {
+    assert_eq!(run("fn main() { return 0b1011 & 0x3 }"), BakhtValue::Int(3));
+    assert_eq!(run("fn main() { return 0b1011 | 4 }"), BakhtValue::Int(15));
+    assert_eq!(run("fn main() { return 0b1011 ^ 1 }"), BakhtValue::Int(10));
+    assert_eq!(run("fn main() { return 1 << 4 }"), BakhtValue::Int(16));
+    assert_eq!(run("fn main() { return 0b1011 >> 1 }"), BakhtValue::Int(5));
+    assert_eq!(run("fn main() { return ~0 }"), BakhtValue::Int(-1));
+    assert_eq!(run("fn main() { return 6.0 & 3 }"), BakhtValue::Int(2));
+    assert_eq!(
+        run("fn main() { return 1 | 2 == 3 }"),
+        BakhtValue::Boolean(true)
+    );
+    assert_eq!(run("fn main() { return 1 + 1 << 2 }"), BakhtValue::Int(8));
+    for src in ["fn main() { return 1.5 & 1 }", "fn main() { return ~'a' }"] {
+        assert!(matches!(run_err(src), Error::NonIntegral));
+    }
+    assert!(matches!(
+        run_err("fn main() { return 1 << 64 }"),
+        Error::Overflow
+    ));
+}
+
+#[test]
+fn power_division_membership() {
+    assert_eq!(
+        run("fn main() { return 2 ** 3 ** 2 }"),
+        BakhtValue::Int(512)
+    );
+    assert_eq!(run("fn main() { return -2 ** 2 }"), BakhtValue::Int(-4));
+    assert_eq!(run("fn main() { return 2 ** -1 }"), BakhtValue::Number(0.5));
+    assert_eq!(
+        run("fn main() { return 2.0 ** 2 }"),
+        BakhtValue::Number(4.0)
+    );
+    assert_eq!(run("fn main() { return 7 // 2 }"), BakhtValue::Int(3));
+    assert_eq!(run("fn main() { return -7 // 2 }"), BakhtValue::Int(-4));
+    assert_eq!(
+        run("fn main() { return 7.5 // 2 }"),
+        BakhtValue::Number(3.0)
+    );
+    assert_eq!(
+        run("fn main() { return 2 in [1, 2] }"),
+        BakhtValue::Boolean(true)
+    );
+    assert_eq!(
+        run("fn main() { return 3 in [1, 2] }"),
+        BakhtValue::Boolean(false)
+    );
+    assert_eq!(
+        run("fn main() { return 'ell' in 'hello' }"),
+        BakhtValue::Boolean(true)
+    );
+    assert_eq!(
+        run("fn main() { return 'k' in { 'k': 1 } }"),
+        BakhtValue::Boolean(true)
+    );
+    assert_eq!(
+        run("fn main() { return 2 in { 2: 'two' } }"),
+        BakhtValue::Boolean(true)
+    );
+    assert_eq!(
+        run("struct Point { x, y } fn main() { return 'x' in Point(1, 2) }"),
+        BakhtValue::Boolean(true)
+    );
+    assert_eq!(
+        run("struct Point { x, y } fn main() { return 'z' in Point(1, 2) }"),
+        BakhtValue::Boolean(false)
+    );
+    assert_eq!(
+        run("fn main() { return not 2 in [1] }"),
+        BakhtValue::Boolean(true)
+    );
+    assert_eq!(
+        run("fn main() { return not 1 in [1] or not false and 1 == 1 }"),
+        BakhtValue::Boolean(true)
+    );
+    assert!(matches!(
+        run_err("fn main() { return 1 // 0 }"),
+        Error::DivisionByZero
+    ));
+    assert!(matches!(
+        run_err("fn main() { return 1 in 2 }"),
+        Error::InvalidOperands
+    ));
+}
+
+#[test]
+fn slicing() {
+    for (slice, expected) in [
+        ("a[1:3]", "[2, 3]"),
+        ("a[:-1]", "[1, 2, 3, 4]"),
+        ("a[-2:]", "[4, 5]"),
+        ("a[::2]", "[1, 3, 5]"),
+        ("a[::-1]", "[5, 4, 3, 2, 1]"),
+        ("a[3:1]", "[]"),
+        ("a[-10:10]", "[1, 2, 3, 4, 5]"),
+    ] {
+        let src = format!(
+            "fn main() {{ let a = [1, 2, 3, 4, 5] return \"{{{}}}\" }}",
+            slice
+        );
+        assert_eq!(run(&src), BakhtValue::String(expected.to_string()));
+    }
+    assert_eq!(
+        run(r#"fn main() { let a = [1, 2, 3] let b = a[1:3] b[0] = 9 return "{a} {b}" }"#),
+        BakhtValue::String("[1, 2, 3] [9, 3]".to_string())
+    );
+    for (slice, expected) in [
+        ("s[1:3]", "él"),
+        ("s[:-1]", "héll"),
+        ("s[::-1]", "olléh"),
+        ("s[1:][1]", "l"),
+    ] {
+        let src = format!("fn main() {{ let s = 'héllo' return {} }}", slice);
+        assert_eq!(run(&src), BakhtValue::String(expected.to_string()));
+    }
+    assert_eq!(
+        run("fn main() { let a = [1, 2, 3, 4, 5] return a[1:4].len() }"),
+        BakhtValue::Int(3)
+    );
+    assert!(matches!(
+        run_err("fn main() { return [1, 2][::0] }"),
+        Error::InvalidOperands
+    ));
+}
@@ -11,18 +11,35 @@ pub(crate) enum TokenKind {
     Single(char),
     Double,
     Error,
+    Unterminated,
+    #[allow(clippy::upper_case_acronyms)]
     EOF,
     Literal,
+    Interpolation,
     // keywords
     Let,
     If,
     Else,
     While,
+    For,
+    In,
     Fn,
+    Struct,
+    Import,
     Nil,
     True,
     False,
     Return,
+    Break,
+    Continue,
+    And,
+    Or,
+    Not,
+    Try,
+    Catch,
+    Throw,
+    Match,
+    Yield,
 }
 
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
@@ -37,7 +54,7 @@ impl Token {
         self.kind == TokenKind::White || self.kind == TokenKind::Comment
     }
     pub(crate) fn is_error(&self) -> bool {
-        self.kind == TokenKind::Error
+        self.kind == TokenKind::Error || self.kind == TokenKind::Unterminated
     }
     pub(crate) fn is(&self, c: char) -> bool {
         self.kind == TokenKind::Single(c)
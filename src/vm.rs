@@ -1,12 +1,54 @@
 use super::BakhtScript;
-use crate::{bin::*, Error};
-use std::{cell::RefCell, sync::Arc};
+use crate::{assemble::encode, bin::*, Error};
+use std::{
+    cell::RefCell,
+    cmp::Ordering,
+    collections::{BTreeMap, HashMap},
+    fmt,
+    rc::{Rc, Weak},
+    sync::Arc,
+};
 pub(crate) type Native = fn(&mut BakhtScript);
 
 #[derive(Clone, Copy)]
 pub(crate) enum Function {
-    Bakht { param_count: usize, address: usize },
-    Native { param_count: usize, func: Native },
+    Bakht {
+        param_count: usize,
+        required: usize,
+        rest: bool,
+        generator: bool,
+        address: usize,
+    },
+    Native {
+        param_count: usize,
+        func: Native,
+    },
+}
+
+pub(crate) enum Upvalue {
+    Open(Option<Weak<RefCell<Coroutine>>>, usize),
+    Closed(Value),
+}
+
+pub(crate) struct Closure {
+    function: Function,
+    upvalues: Vec<Rc<RefCell<Upvalue>>>,
+}
+
+pub(crate) struct Struct {
+    fields: Vec<usize>,
+    slots: Vec<Option<usize>>,
+}
+
+pub(crate) struct Instance {
+    layout: Rc<Struct>,
+    slots: RefCell<Vec<Value>>,
+}
+
+impl Instance {
+    fn slot(&self, field: usize) -> Option<usize> {
+        self.layout.slots.get(field).copied().flatten()
+    }
 }
 
 #[derive(PartialEq)]
@@ -24,33 +66,252 @@ impl Array {
     pub(crate) fn len(&self) -> usize {
         self.inner.borrow().len()
     }
+    fn contains(&self, value: &Value) -> bool {
+        self.inner.borrow().contains(value)
+    }
     fn get(&self, index: usize) -> Option<Value> {
         self.inner.borrow().get(index).cloned()
     }
     fn set(&self, index: usize, value: Value) -> bool {
-        let arr = self.inner.borrow_mut();
+        let mut arr = self.inner.borrow_mut();
         if arr.len() <= index {
             false
         } else {
-            self.inner.borrow_mut()[index] = value;
+            arr[index] = value;
             true
         }
     }
-    fn new(array: Vec<Value>) -> Array {
+    pub(crate) fn new(array: Vec<Value>) -> Array {
         Array {
             inner: RefCell::new(array),
         }
     }
 }
 
+#[derive(Clone)]
+pub(crate) enum Key {
+    Boolean(bool),
+    Int(i64),
+    Number(f64),
+    String(Arc<String>),
+}
+
+impl Key {
+    fn new(value: Value) -> Option<Key> {
+        match value {
+            Value::Boolean(b) => Some(Key::Boolean(b)),
+            Value::Int(n) => Some(Key::Int(n)),
+            Value::Number(n) if n.fract() == 0.0 && n.abs() < i64::MAX as f64 => {
+                Some(Key::Int(n as i64))
+            }
+            Value::Number(n) => Some(Key::Number(n + 0.0)),
+            Value::String(s) => Some(Key::String(s)),
+            _ => None,
+        }
+    }
+    fn value(&self) -> Value {
+        match self {
+            Key::Boolean(b) => Value::Boolean(*b),
+            Key::Int(n) => Value::Int(*n),
+            Key::Number(n) => Value::Number(*n),
+            Key::String(s) => Value::String(s.clone()),
+        }
+    }
+}
+
+impl Ord for Key {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (Key::Boolean(l0), Key::Boolean(r0)) => l0.cmp(r0),
+            (Key::Int(l0), Key::Int(r0)) => l0.cmp(r0),
+            (Key::Int(l0), Key::Number(r0)) => (*l0 as f64).total_cmp(r0),
+            (Key::Number(l0), Key::Int(r0)) => l0.total_cmp(&(*r0 as f64)),
+            (Key::Number(l0), Key::Number(r0)) => l0.total_cmp(r0),
+            (Key::String(l0), Key::String(r0)) => l0.cmp(r0),
+            (Key::Boolean(_), _) => Ordering::Less,
+            (_, Key::Boolean(_)) => Ordering::Greater,
+            (Key::String(_), _) => Ordering::Greater,
+            (_, Key::String(_)) => Ordering::Less,
+        }
+    }
+}
+
+impl PartialOrd for Key {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Key {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Key {}
+
+#[derive(PartialEq)]
+pub struct Map {
+    inner: RefCell<BTreeMap<Key, Value>>,
+}
+
+impl Map {
+    pub(crate) fn len(&self) -> usize {
+        self.inner.borrow().len()
+    }
+    pub(crate) fn keys(&self) -> Vec<Value> {
+        self.inner.borrow().keys().map(|k| k.value()).collect()
+    }
+    pub(crate) fn values(&self) -> Vec<Value> {
+        self.inner.borrow().values().cloned().collect()
+    }
+    pub(crate) fn has(&self, key: Value) -> Option<bool> {
+        Some(self.inner.borrow().contains_key(&Key::new(key)?))
+    }
+    pub(crate) fn remove(&self, key: Value) -> Option<Value> {
+        Some(
+            self.inner
+                .borrow_mut()
+                .remove(&Key::new(key)?)
+                .unwrap_or(Value::Nil),
+        )
+    }
+    fn get(&self, key: Value) -> Option<Value> {
+        Some(
+            self.inner
+                .borrow()
+                .get(&Key::new(key)?)
+                .cloned()
+                .unwrap_or(Value::Nil),
+        )
+    }
+    fn set(&self, key: Value, value: Value) -> bool {
+        match Key::new(key) {
+            Some(key) => {
+                self.inner.borrow_mut().insert(key, value);
+                true
+            }
+            None => false,
+        }
+    }
+    fn new(map: BTreeMap<Key, Value>) -> Map {
+        Map {
+            inner: RefCell::new(map),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub(crate) enum Value {
     String(Arc<String>),
-    Array(Arc<Array>),
+    Array(Rc<Array>),
+    Map(Rc<Map>),
     Nil,
     Boolean(bool),
-    Number(f32),
+    Int(i64),
+    Number(f64),
     Function(Function),
+    Closure(Rc<Closure>),
+    Struct(Rc<Struct>),
+    Instance(Rc<Instance>),
+    Coroutine(Rc<RefCell<Coroutine>>),
+    Bound(Rc<Bound>),
+}
+
+pub(crate) struct Bound {
+    receiver: Value,
+    method: Value,
+}
+
+impl Value {
+    pub(crate) fn truthy(&self) -> bool {
+        !matches!(self, Value::Nil | Value::Boolean(false))
+    }
+    pub(crate) fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::Array(_) => "array",
+            Value::Map(_) => "map",
+            Value::Nil => "nil",
+            Value::Boolean(_) => "boolean",
+            Value::Int(_) => "int",
+            Value::Number(_) => "float",
+            Value::Function(_) | Value::Closure(_) | Value::Bound(_) => "function",
+            Value::Struct(_) => "struct",
+            Value::Instance(_) => "instance",
+            Value::Coroutine(_) => "coroutine",
+        }
+    }
+    fn integral(&self) -> Option<i64> {
+        match self {
+            Value::Int(n) => Some(*n),
+            Value::Number(n) if n.fract() == 0.0 && n.abs() < i64::MAX as f64 => Some(*n as i64),
+            _ => None,
+        }
+    }
+    fn float(&self) -> Option<f64> {
+        match self {
+            Value::Int(n) => Some(*n as f64),
+            Value::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+    fn display(&self, f: &mut fmt::Formatter<'_>, seen: &mut Vec<*const ()>) -> fmt::Result {
+        match self {
+            Value::Array(a) => {
+                let ptr = Rc::as_ptr(a) as *const ();
+                if seen.contains(&ptr) {
+                    return write!(f, "[...]");
+                }
+                seen.push(ptr);
+                write!(f, "[")?;
+                for (i, ele) in a.inner.borrow().iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    ele.display(f, seen)?;
+                }
+                seen.pop();
+                write!(f, "]")
+            }
+            Value::Map(m) => {
+                let ptr = Rc::as_ptr(m) as *const ();
+                if seen.contains(&ptr) {
+                    return write!(f, "{{...}}");
+                }
+                seen.push(ptr);
+                write!(f, "{{")?;
+                for (i, (key, value)) in m.inner.borrow().iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}: ", key.value())?;
+                    value.display(f, seen)?;
+                }
+                seen.pop();
+                write!(f, "}}")
+            }
+            value => write!(f, "{}", value),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::String(s) => write!(f, "{}", s),
+            Value::Array(_) | Value::Map(_) => self.display(f, &mut vec![]),
+            Value::Nil => write!(f, "nil"),
+            Value::Boolean(b) => write!(f, "{}", b),
+            Value::Int(n) => write!(f, "{}", n),
+            Value::Number(n) => write!(f, "{:?}", n),
+            Value::Function(_) | Value::Closure(_) | Value::Struct(_) | Value::Bound(_) => {
+                write!(f, "<function>")
+            }
+            Value::Instance(_) => write!(f, "<struct>"),
+            Value::Coroutine(_) => write!(f, "<coroutine>"),
+        }
+    }
 }
 
 impl PartialEq for Value {
@@ -59,19 +320,16 @@ impl PartialEq for Value {
             (Self::String(l0), Self::String(r0)) => l0 == r0,
             (Self::Nil, Self::Nil) => true,
             (Self::Array(l0), Self::Array(r0)) => l0 == r0,
+            (Self::Map(l0), Self::Map(r0)) => l0 == r0,
             (Self::Boolean(l0), Self::Boolean(r0)) => l0 == r0,
+            (Self::Int(l0), Self::Int(r0)) => l0 == r0,
             (Self::Number(l0), Self::Number(r0)) => l0 == r0,
+            (Self::Int(l0), Self::Number(r0)) => *l0 as f64 == *r0,
+            (Self::Number(l0), Self::Int(r0)) => *l0 == *r0 as f64,
             (Self::Function(l0), Self::Function(r0)) => match (l0, r0) {
-                (
-                    Function::Bakht {
-                        param_count: _,
-                        address: l0,
-                    },
-                    Function::Bakht {
-                        param_count: _,
-                        address: r0,
-                    },
-                ) => l0 == r0,
+                (Function::Bakht { address: l0, .. }, Function::Bakht { address: r0, .. }) => {
+                    l0 == r0
+                }
                 (
                     Function::Native {
                         param_count: _,
@@ -84,32 +342,103 @@ impl PartialEq for Value {
                 ) => (*l0 as usize) == (*r0 as usize),
                 _ => false,
             },
+            (Self::Closure(l0), Self::Closure(r0)) => Rc::ptr_eq(l0, r0),
+            (Self::Struct(l0), Self::Struct(r0)) => Rc::ptr_eq(l0, r0),
+            (Self::Instance(l0), Self::Instance(r0)) => Rc::ptr_eq(l0, r0),
+            (Self::Coroutine(l0), Self::Coroutine(r0)) => Rc::ptr_eq(l0, r0),
+            (Self::Bound(l0), Self::Bound(r0)) => Rc::ptr_eq(l0, r0),
             _ => false,
         }
     }
 }
 
 pub(crate) trait VM {
-    fn rodata_function(&mut self, param_count: usize, entry: bool) -> usize;
+    fn rodata_function(
+        &mut self,
+        param_count: usize,
+        required: usize,
+        rest: bool,
+        entry: bool,
+    ) -> usize;
     fn rodata_native(&mut self, native: Native, param_count: usize) -> usize;
+    fn rodata_struct(&mut self, fields: Vec<usize>) -> usize;
+    fn global(&mut self) -> usize;
+    fn initializer(&mut self, function: usize);
+    fn define(&mut self, slot: usize, konst: usize);
     fn emit(&mut self, bytecode: u8) -> usize;
     fn edit(&mut self, bytecode: u8, address: usize);
-    fn rodata_number(&mut self, number: f32) -> usize;
+    fn address(&self) -> usize;
+    fn rodata_int(&mut self, number: i64) -> usize;
+    fn rodata_number(&mut self, number: f64) -> usize;
     fn rodata_literal(&mut self, literal: String) -> usize;
+    fn error_struct(&mut self, idx: usize);
+    fn generator(&mut self, function: usize);
+    fn rodata_name(&mut self, name: String);
+}
+
+struct Handler {
+    address: usize,
+    sp: usize,
 }
 
 pub(crate) struct Frame {
     ip: usize,
     bp: usize,
+    closure: Option<Rc<Closure>>,
+    handlers: Vec<Handler>,
 }
 
+pub(crate) struct Coroutine {
+    stack: Vec<Value>,
+    frames: Vec<Frame>,
+    upvalues: Vec<Rc<RefCell<Upvalue>>>,
+    started: bool,
+    running: bool,
+}
+
+impl Coroutine {
+    pub(crate) fn done(&self) -> bool {
+        self.frames.is_empty() && !self.running
+    }
+}
+
+impl Drop for Coroutine {
+    fn drop(&mut self) {
+        for upvalue in self.upvalues.iter() {
+            let mut upvalue = upvalue.borrow_mut();
+            if let Upvalue::Open(_, address) = *upvalue {
+                *upvalue = Upvalue::Closed(self.stack[address].clone());
+            }
+        }
+    }
+}
+
+struct Suspended {
+    owner: Option<Weak<RefCell<Coroutine>>>,
+    stack: Vec<Value>,
+    frames: Vec<Frame>,
+    upvalues: Vec<Rc<RefCell<Upvalue>>>,
+}
+
+#[allow(clippy::upper_case_acronyms)]
 pub(crate) struct BVM {
     stack: Vec<Value>,
     bin: Vec<u8>,
     constants: Vec<Value>,
     frames: Vec<Frame>,
+    upvalues: Vec<Rc<RefCell<Upvalue>>>,
+    coroutine: Option<Weak<RefCell<Coroutine>>>,
+    suspended: Vec<Suspended>,
+    globals: Vec<Value>,
+    initializers: Vec<usize>,
     entry: usize,
+    error_struct: usize,
     error: Option<Error>,
+    exception: Option<Value>,
+    yielded: Option<Value>,
+    names: Vec<String>,
+    methods: HashMap<&'static str, HashMap<String, Value>>,
+    offsets: Option<(Arc<String>, Vec<usize>)>,
 }
 
 impl VM for BVM {
@@ -123,7 +452,15 @@ impl VM for BVM {
     fn edit(&mut self, bytecode: u8, address: usize) {
         self.bin[address] = bytecode;
     }
-    fn rodata_number(&mut self, number: f32) -> usize {
+    fn address(&self) -> usize {
+        self.bin.len()
+    }
+    fn rodata_int(&mut self, number: i64) -> usize {
+        let idx = self.constants.len();
+        self.constants.push(Value::Int(number));
+        idx
+    }
+    fn rodata_number(&mut self, number: f64) -> usize {
         let idx = self.constants.len();
         self.constants.push(Value::Number(number));
         idx
@@ -133,20 +470,59 @@ impl VM for BVM {
         self.constants.push(Value::String(Arc::new(literal)));
         idx
     }
-    fn rodata_function(&mut self, param_count: usize, entry: bool) -> usize {
+    fn rodata_function(
+        &mut self,
+        param_count: usize,
+        required: usize,
+        rest: bool,
+        entry: bool,
+    ) -> usize {
         let address = self.bin.len();
         let idx = self.constants.len();
         let val = Value::Function(Function::Bakht {
             param_count,
+            required,
+            rest,
+            generator: false,
             address,
         });
-        self.constants.push(val.clone());
+        self.constants.push(val);
         if entry {
             self.entry = idx;
         }
-        self.push(val);
         idx
     }
+    fn rodata_struct(&mut self, fields: Vec<usize>) -> usize {
+        let idx = self.constants.len();
+        let mut slots = vec![None; fields.iter().max().map_or(0, |f| f + 1)];
+        for (slot, field) in fields.iter().enumerate() {
+            slots[*field] = Some(slot);
+        }
+        self.constants
+            .push(Value::Struct(Rc::new(Struct { fields, slots })));
+        idx
+    }
+    fn global(&mut self) -> usize {
+        self.globals.push(Value::Nil);
+        self.globals.len() - 1
+    }
+    fn initializer(&mut self, function: usize) {
+        self.initializers.push(function);
+    }
+    fn define(&mut self, slot: usize, konst: usize) {
+        self.globals[slot] = self.constants[konst].clone();
+    }
+    fn error_struct(&mut self, idx: usize) {
+        self.error_struct = idx;
+    }
+    fn rodata_name(&mut self, name: String) {
+        self.names.push(name);
+    }
+    fn generator(&mut self, function: usize) {
+        if let Value::Function(Function::Bakht { generator, .. }) = &mut self.constants[function] {
+            *generator = true;
+        }
+    }
     fn rodata_native(&mut self, func: Native, param_count: usize) -> usize {
         let idx = self.constants.len();
         self.constants
@@ -159,17 +535,44 @@ impl BVM {
     pub fn fcall(&mut self, argc: usize) {
         self.i_call(argc)
     }
+    pub fn entry(&mut self) {
+        for idx in std::mem::take(&mut self.initializers) {
+            self.i_konst(idx);
+            self.i_call(0);
+            if self.error.is_some() {
+                return;
+            }
+            self.pop();
+        }
+        let main = self.constants[self.entry].clone();
+        self.push(main)
+    }
     pub fn reset(&mut self) {
         self.bin.clear();
         self.constants.clear();
         self.stack.clear();
         self.frames.clear();
+        self.upvalues.clear();
+        self.coroutine = None;
+        self.suspended.clear();
+        self.globals.clear();
+        self.initializers.clear();
         self.error = None;
+        self.exception = None;
+        self.yielded = None;
+        self.names.clear();
+        self.offsets = None;
         self.entry = 0;
+        self.error_struct = 0;
         self.init();
     }
     pub fn init(&mut self) {
-        self.frames.push(Frame { ip: 0, bp: 0 })
+        self.frames.push(Frame {
+            ip: 0,
+            bp: 0,
+            closure: None,
+            handlers: vec![],
+        })
     }
     pub fn push(&mut self, value: Value) {
         self.stack.push(value)
@@ -195,11 +598,10 @@ impl BVM {
             let operand_count = (opcode & 0b_1100_0000) >> 6;
             let operand_count = 1 << operand_count;
             let mut operand = 0usize;
-            for _ in 0..operand_count {
-                operand = operand << 8;
-                operand += self.read() as usize;
+            for i in 0..operand_count {
+                operand |= (self.read() as usize) << (i * 8);
             }
-            opcode &= 0b_0011_11111;
+            opcode &= 0b_0011_1111;
             operand
         } else {
             0
@@ -207,7 +609,7 @@ impl BVM {
         (opcode, operand)
     }
     fn process(&mut self) {
-        while self.error.is_none() {
+        loop {
             let (opcode, operand) = self.fetch();
             match opcode {
                 IADD => self.i_add(),
@@ -232,14 +634,206 @@ impl BVM {
                 ITRUE => self.i_true(),
                 IFALSE => self.i_false(),
                 IANEW => self.i_anew(operand),
+                IMNEW => self.i_mnew(operand),
+                IGETFIELD => self.i_getfield(operand),
+                ISETFIELD => self.i_setfield(operand),
+                IGLOAD => self.i_gload(operand),
+                IGSTORE => self.i_gstore(operand),
+                IDUP => self.i_dup(operand),
+                ISTR => self.i_str(),
                 IMOD => self.i_mod(),
+                IJMP => self.i_jmp(operand),
+                ICJMP => self.i_cjmp(operand),
+                INEXT => self.i_next(operand),
+                INOT => self.i_not(),
+                IAND => self.i_and(operand),
+                IOR => self.i_or(operand),
+                ICAPTURE => self.i_capture(operand),
+                ILOADUP => self.i_loadup(operand),
+                ISTOREUP => self.i_storeup(operand),
+                ITRY => self.i_try(operand),
+                IUNTRY => self.i_untry(operand),
+                ITHROW => self.i_throw(),
+                ISHAPE => self.i_shape(operand),
+                IUNPACK => self.i_unpack(operand),
+                IYIELD => self.i_yield(),
+                IMETHOD => self.i_method(operand),
+                IBAND => self.i_band(),
+                IBOR => self.i_bor(),
+                IBXOR => self.i_bxor(),
+                ISHL => self.i_shl(),
+                ISHR => self.i_shr(),
+                IBNOT => self.i_bnot(),
+                IPOW => self.i_pow(),
+                IIDIV => self.i_idiv(),
+                IIN => self.i_in(),
+                ISLICE => self.i_slice(),
+                INOP => (),
                 _ => panic!(),
             }
-            if opcode == IRET {
+            if self.error.is_some() && !self.catch() {
+                self.frames.pop();
+                break;
+            }
+            if opcode == IRET || opcode == IYIELD {
                 break;
             }
         }
     }
+    fn catch(&mut self) -> bool {
+        let handler = match self.frames.last_mut().unwrap().handlers.pop() {
+            Some(handler) => handler,
+            None => return false,
+        };
+        let error = self.error.take().unwrap();
+        let value = match self.exception.take() {
+            Some(value) => value,
+            None => self.error_value(error),
+        };
+        self.close_upvalues(handler.sp);
+        self.stack.truncate(handler.sp);
+        self.push(value);
+        *self.ip() = handler.address;
+        true
+    }
+    fn error_value(&self, error: Error) -> Value {
+        let layout = match &self.constants[self.error_struct] {
+            Value::Struct(layout) => layout.clone(),
+            _ => return Value::String(Arc::new(error.to_string())),
+        };
+        let slots = vec![
+            Value::String(Arc::new(error.kind().to_string())),
+            Value::String(Arc::new(error.to_string())),
+        ];
+        Value::Instance(Rc::new(Instance {
+            layout,
+            slots: RefCell::new(slots),
+        }))
+    }
+    fn i_try(&mut self, address: usize) {
+        let sp = self.sp();
+        self.frames
+            .last_mut()
+            .unwrap()
+            .handlers
+            .push(Handler { address, sp });
+    }
+    fn i_untry(&mut self, count: usize) {
+        let handlers = &mut self.frames.last_mut().unwrap().handlers;
+        handlers.truncate(handlers.len() - count);
+    }
+    fn i_throw(&mut self) {
+        let value = self.pop();
+        self.error = Some(Error::Thrown(value.to_string()));
+        self.exception = Some(value);
+    }
+    fn i_shape(&mut self, operand: usize) {
+        let (count, rest) = (operand >> 1, operand & 1 == 1);
+        let matched = match self.pop() {
+            Value::Array(array) => array.len() == count || (rest && array.len() >= count),
+            _ => false,
+        };
+        self.push(Value::Boolean(matched))
+    }
+    fn i_unpack(&mut self, operand: usize) {
+        let (count, rest) = (operand >> 1, operand & 1 == 1);
+        match self.pop() {
+            Value::Array(array) if array.len() == count || (rest && array.len() >= count) => {
+                let mut elements = array.inner.borrow().clone();
+                let tail = elements.split_off(count);
+                self.stack.extend(elements);
+                if rest {
+                    self.push(Value::Array(Rc::new(Array::new(tail))));
+                }
+            }
+            _ => self.error = Some(Error::ShapeMismatch),
+        }
+    }
+    pub(crate) fn add_method(
+        &mut self,
+        kind: &'static str,
+        name: &str,
+        func: Native,
+        param_count: usize,
+    ) {
+        let method = Value::Function(Function::Native { func, param_count });
+        self.methods
+            .entry(kind)
+            .or_default()
+            .insert(name.to_string(), method);
+    }
+    fn i_method(&mut self, name: usize) {
+        let receiver = self.pop();
+        if let Value::Instance(instance) = &receiver {
+            if let Some(slot) = instance.slot(name) {
+                let value = instance.slots.borrow()[slot].clone();
+                self.push(value);
+                return;
+            }
+        }
+        let method = self
+            .methods
+            .get(receiver.type_name())
+            .and_then(|methods| methods.get(&self.names[name]))
+            .cloned();
+        match method {
+            Some(method) => self.push(Value::Bound(Rc::new(Bound { receiver, method }))),
+            None => self.error = Some(Error::NoSuchMethod(self.names[name].clone())),
+        }
+    }
+    fn i_yield(&mut self) {
+        let value = self.pop();
+        self.yielded = Some(value);
+    }
+    pub(crate) fn resume(
+        &mut self,
+        coroutine: &Rc<RefCell<Coroutine>>,
+        sent: Value,
+    ) -> Option<Value> {
+        {
+            let owner = Rc::downgrade(coroutine);
+            let mut coroutine = coroutine.borrow_mut();
+            if coroutine.running {
+                self.error = Some(Error::InvalidOperands);
+                return None;
+            }
+            if coroutine.done() {
+                return None;
+            }
+            coroutine.running = true;
+            let caller = Suspended {
+                owner: self.coroutine.replace(owner),
+                stack: std::mem::replace(&mut self.stack, std::mem::take(&mut coroutine.stack)),
+                frames: std::mem::replace(&mut self.frames, std::mem::take(&mut coroutine.frames)),
+                upvalues: std::mem::replace(
+                    &mut self.upvalues,
+                    std::mem::take(&mut coroutine.upvalues),
+                ),
+            };
+            self.suspended.push(caller);
+            if coroutine.started {
+                self.push(sent);
+            }
+            coroutine.started = true;
+        }
+        self.process();
+        let yielded = self.yielded.take();
+        if yielded.is_none() {
+            self.close_upvalues(0);
+        }
+        let caller = self.suspended.pop().unwrap();
+        let mut coroutine = coroutine.borrow_mut();
+        self.coroutine = caller.owner;
+        coroutine.stack = std::mem::replace(&mut self.stack, caller.stack);
+        coroutine.frames = std::mem::replace(&mut self.frames, caller.frames);
+        coroutine.upvalues = std::mem::replace(&mut self.upvalues, caller.upvalues);
+        coroutine.running = false;
+        if yielded.is_none() {
+            coroutine.frames.clear();
+            coroutine.stack.clear();
+        }
+        yielded
+    }
     fn i_load(&mut self, operand: usize) {
         let address = self.bp() + operand;
         let value = self.stack[address].clone();
@@ -250,81 +844,160 @@ impl BVM {
         let address = self.bp() + operand;
         self.stack[address] = value;
     }
+    fn i_str(&mut self) {
+        let value = match self.pop() {
+            Value::String(s) => Value::String(s),
+            value => Value::String(Arc::new(value.to_string())),
+        };
+        self.push(value)
+    }
+    fn i_dup(&mut self, count: usize) {
+        let from = self.sp() - count;
+        self.stack.extend_from_within(from..);
+    }
+    fn i_gload(&mut self, operand: usize) {
+        let value = self.globals[operand].clone();
+        self.push(value)
+    }
+    fn i_gstore(&mut self, operand: usize) {
+        self.globals[operand] = self.pop();
+    }
     fn i_konst(&mut self, operand: usize) {
         let value = self.constants[operand].clone();
         self.push(value)
     }
-    fn number(&mut self, value: f32) -> Value {
-        Value::Number(value)
-    }
-    fn i_add(&mut self) {
+    fn arithmetic(&mut self, int: fn(i64, i64) -> Option<i64>, float: fn(f64, f64) -> f64) {
         let b = self.pop();
         let a = self.pop();
         match (a, b) {
-            (Value::Number(a), Value::Number(b)) => {
-                let value = self.number(a + b);
-                self.push(value)
-            }
-            (Value::String(a), Value::String(b)) => {
-                let mut c = a.to_string();
-                c.push_str(b.as_str());
-                let value = Value::String(Arc::new(c));
-                self.push(value)
-            }
-            _ => self.error = Some(Error::InvalidOperands),
+            (Value::Int(a), Value::Int(b)) => match int(a, b) {
+                Some(n) => self.push(Value::Int(n)),
+                None => self.error = Some(Error::Overflow),
+            },
+            (a, b) => match (a.float(), b.float()) {
+                (Some(a), Some(b)) => self.push(Value::Number(float(a, b))),
+                _ => self.error = Some(Error::InvalidOperands),
+            },
+        }
+    }
+    fn zero_divisor(&mut self) -> bool {
+        let zero = match self.stack.last() {
+            Some(Value::Int(n)) => *n == 0,
+            Some(Value::Number(n)) => *n == 0.0,
+            _ => false,
         };
+        if zero {
+            self.error = Some(Error::DivisionByZero);
+        }
+        zero
+    }
+    fn i_add(&mut self) {
+        let sp = self.sp();
+        if let (Value::String(a), Value::String(b)) = (&self.stack[sp - 2], &self.stack[sp - 1]) {
+            let value = Value::String(Arc::new(format!("{}{}", a, b)));
+            self.pop();
+            self.pop();
+            self.push(value)
+        } else {
+            self.arithmetic(i64::checked_add, |a, b| a + b)
+        }
     }
     fn i_sub(&mut self) {
-        let b = self.pop();
-        let a = self.pop();
-        match (a, b) {
-            (Value::Number(a), Value::Number(b)) => {
-                let value = self.number(a - b);
-                self.push(value)
-            }
-            _ => self.error = Some(Error::InvalidOperands),
-        };
+        self.arithmetic(i64::checked_sub, |a, b| a - b)
     }
     fn i_mult(&mut self) {
-        let b = self.pop();
-        let a = self.pop();
-        match (a, b) {
-            (Value::Number(a), Value::Number(b)) => {
-                let value = self.number(a * b);
-                self.push(value)
-            }
-            _ => self.error = Some(Error::InvalidOperands),
-        };
+        self.arithmetic(i64::checked_mul, |a, b| a * b)
     }
     fn i_div(&mut self) {
-        let b = self.pop();
-        let a = self.pop();
-        match (a, b) {
-            (Value::Number(a), Value::Number(b)) => {
-                if b == 0.0 {
-                    self.error = Some(Error::DivisionByZero);
-                } else {
-                    let value = self.number(a / b);
-                    self.push(value)
-                }
-            }
-            _ => self.error = Some(Error::InvalidOperands),
-        };
+        if !self.zero_divisor() {
+            self.arithmetic(i64::checked_div, |a, b| a / b)
+        }
     }
     fn i_mod(&mut self) {
+        if !self.zero_divisor() {
+            self.arithmetic(i64::checked_rem, |a, b| a % b)
+        }
+    }
+    fn bitwise(&mut self, op: fn(i64, i64) -> Option<i64>) {
         let b = self.pop();
         let a = self.pop();
-        match (a, b) {
-            (Value::Number(a), Value::Number(b)) => {
-                if b == 0.0 {
-                    self.error = Some(Error::DivisionByZero);
-                } else {
-                    let value = self.number(a % b);
-                    self.push(value)
-                }
+        match (a.integral(), b.integral()) {
+            (Some(a), Some(b)) => match op(a, b) {
+                Some(n) => self.push(Value::Int(n)),
+                None => self.error = Some(Error::Overflow),
+            },
+            _ => self.error = Some(Error::NonIntegral),
+        }
+    }
+    fn i_band(&mut self) {
+        self.bitwise(|a, b| Some(a & b))
+    }
+    fn i_bor(&mut self) {
+        self.bitwise(|a, b| Some(a | b))
+    }
+    fn i_bxor(&mut self) {
+        self.bitwise(|a, b| Some(a ^ b))
+    }
+    fn i_shl(&mut self) {
+        self.bitwise(|a, b| a.checked_shl(u32::try_from(b).ok()?))
+    }
+    fn i_shr(&mut self) {
+        self.bitwise(|a, b| a.checked_shr(u32::try_from(b).ok()?))
+    }
+    fn i_bnot(&mut self) {
+        match self.pop().integral() {
+            Some(n) => self.push(Value::Int(!n)),
+            None => self.error = Some(Error::NonIntegral),
+        }
+    }
+    fn i_pow(&mut self) {
+        let sp = self.sp();
+        if let (Value::Int(a), Value::Int(b)) = (&self.stack[sp - 2], &self.stack[sp - 1]) {
+            if *b < 0 {
+                let value = Value::Number((*a as f64).powf(*b as f64));
+                self.pop();
+                self.pop();
+                self.push(value);
+                return;
             }
-            _ => self.error = Some(Error::InvalidOperands),
+        }
+        self.arithmetic(|a, b| a.checked_pow(u32::try_from(b).ok()?), f64::powf)
+    }
+    fn i_idiv(&mut self) {
+        if !self.zero_divisor() {
+            self.arithmetic(
+                |a, b| {
+                    let q = a.checked_div(b)?;
+                    if a % b != 0 && (a < 0) != (b < 0) {
+                        Some(q - 1)
+                    } else {
+                        Some(q)
+                    }
+                },
+                |a, b| (a / b).floor(),
+            )
+        }
+    }
+    fn i_in(&mut self) {
+        let container = self.pop();
+        let value = self.pop();
+        let result = match (&value, &container) {
+            (_, Value::Array(array)) => Some(array.contains(&value)),
+            (Value::String(s), Value::String(text)) => Some(text.contains(s.as_str())),
+            (_, Value::Map(map)) => map.has(value.clone()),
+            (Value::String(s), Value::Instance(instance)) => Some(
+                self.names
+                    .iter()
+                    .position(|name| name == s.as_str())
+                    .and_then(|field| instance.slot(field))
+                    .is_some(),
+            ),
+            _ => None,
         };
+        match result {
+            Some(result) => self.push(Value::Boolean(result)),
+            None => self.error = Some(Error::InvalidOperands),
+        }
     }
     fn i_true(&mut self) {
         self.push(Value::Boolean(true))
@@ -341,7 +1014,24 @@ impl BVM {
             elements.push(self.pop());
         }
         elements.reverse();
-        self.push(Value::Array(Arc::new(Array::new(elements))));
+        self.push(Value::Array(Rc::new(Array::new(elements))));
+    }
+    fn i_mnew(&mut self, count: usize) {
+        let mut map = BTreeMap::new();
+        let entries = self.stack.split_off(self.sp() - 2 * count);
+        let mut entries = entries.into_iter();
+        while let (Some(key), Some(value)) = (entries.next(), entries.next()) {
+            match Key::new(key) {
+                Some(key) => {
+                    map.insert(key, value);
+                }
+                None => {
+                    self.error = Some(Error::InvalidOperands);
+                    return;
+                }
+            }
+        }
+        self.push(Value::Map(Rc::new(Map::new(map))));
     }
     fn i_eq(&mut self) {
         let b = self.pop();
@@ -353,49 +1043,44 @@ impl BVM {
         let a = self.pop();
         self.push(Value::Boolean(a != b));
     }
-    fn i_gt(&mut self) {
+    fn compare(&mut self, test: fn(Ordering) -> bool) {
         let b = self.pop();
         let a = self.pop();
-        match (a, b) {
-            (Value::Number(l0), Value::Number(r0)) => self.push(Value::Boolean(l0 > r0)),
-            (Value::String(l0), Value::String(r0)) => self.push(Value::Boolean(l0 > r0)),
-            _ => self.error = Some(Error::InvalidOperands),
-        }
+        let result = match (&a, &b) {
+            (Value::Int(l0), Value::Int(r0)) => test(l0.cmp(r0)),
+            (Value::String(l0), Value::String(r0)) => test(l0.cmp(r0)),
+            _ => match (a.float(), b.float()) {
+                (Some(l0), Some(r0)) => l0.partial_cmp(&r0).is_some_and(test),
+                _ => {
+                    self.error = Some(Error::InvalidOperands);
+                    return;
+                }
+            },
+        };
+        self.push(Value::Boolean(result))
+    }
+    fn i_gt(&mut self) {
+        self.compare(Ordering::is_gt)
     }
     fn i_lt(&mut self) {
-        let b = self.pop();
-        let a = self.pop();
-        match (a, b) {
-            (Value::Number(l0), Value::Number(r0)) => self.push(Value::Boolean(l0 > r0)),
-            (Value::String(l0), Value::String(r0)) => self.push(Value::Boolean(l0 > r0)),
-            _ => self.error = Some(Error::InvalidOperands),
-        }
+        self.compare(Ordering::is_lt)
     }
     fn i_ge(&mut self) {
-        let b = self.pop();
-        let a = self.pop();
-        match (a, b) {
-            (Value::Number(l0), Value::Number(r0)) => self.push(Value::Boolean(l0 >= r0)),
-            (Value::String(l0), Value::String(r0)) => self.push(Value::Boolean(l0 >= r0)),
-            _ => self.error = Some(Error::InvalidOperands),
-        }
+        self.compare(Ordering::is_ge)
     }
     fn i_le(&mut self) {
-        let b = self.pop();
-        let a = self.pop();
-        match (a, b) {
-            (Value::Number(l0), Value::Number(r0)) => self.push(Value::Boolean(l0 <= r0)),
-            (Value::String(l0), Value::String(r0)) => self.push(Value::Boolean(l0 <= r0)),
-            _ => self.error = Some(Error::InvalidOperands),
-        }
+        self.compare(Ordering::is_le)
     }
     fn i_pop(&mut self, count: usize) {
+        self.close_upvalues(self.sp() - count);
         for _ in 0..count {
             self.pop();
         }
     }
     fn i_ret(&mut self) {
         let yld = self.pop();
+        let bp = self.bp();
+        self.close_upvalues(bp);
         while self.sp() != self.bp() {
             self.pop();
         }
@@ -406,72 +1091,348 @@ impl BVM {
         let idx = self.pop();
         let val = self.pop();
         match (val, idx) {
-            (Value::Array(v), Value::Number(i)) => match v.get(i as usize) {
+            (Value::Array(v), Value::Int(i)) => {
+                match usize::try_from(i).ok().and_then(|i| v.get(i)) {
+                    Some(ele) => self.push(ele),
+                    None => self.error = Some(Error::IndexOutOfBound),
+                }
+            }
+            (Value::String(v), Value::Int(i)) => {
+                match usize::try_from(i).ok().and_then(|i| self.char_at(&v, i)) {
+                    Some(ele) => self.push(Value::String(Arc::new(ele.to_string()))),
+                    None => self.error = Some(Error::IndexOutOfBound),
+                }
+            }
+            (Value::Map(m), key) => match m.get(key) {
                 Some(ele) => self.push(ele),
-                None => self.error = Some(Error::IndexOutOfBound),
-            },
-            (Value::String(v), Value::Number(i)) => match v.chars().nth(i as usize) {
-                Some(ele) => self.push(Value::String(Arc::new(ele.to_string()))),
-                None => self.error = Some(Error::IndexOutOfBound),
+                None => self.error = Some(Error::InvalidOperands),
             },
             _ => self.error = Some(Error::InvalidOperands),
         }
     }
+    fn char_at(&mut self, string: &Arc<String>, index: usize) -> Option<char> {
+        if !matches!(&self.offsets, Some((s, _)) if Arc::ptr_eq(s, string)) {
+            let offsets = string.char_indices().map(|(i, _)| i).collect();
+            self.offsets = Some((string.clone(), offsets));
+        }
+        let (_, offsets) = self.offsets.as_ref().unwrap();
+        string[*offsets.get(index)?..].chars().next()
+    }
+    fn i_slice(&mut self) {
+        let step = self.pop();
+        let end = self.pop();
+        let start = self.pop();
+        match self.pop() {
+            Value::Array(v) => {
+                let elements = v.inner.borrow();
+                match slice_indices(elements.len(), start, end, step) {
+                    Some(indices) => {
+                        let array = indices.map(|i| elements[i].clone()).collect();
+                        drop(elements);
+                        self.push(Value::Array(Rc::new(Array::new(array))));
+                    }
+                    None => self.error = Some(Error::InvalidOperands),
+                }
+            }
+            Value::String(v) => {
+                let chars: Vec<char> = v.chars().collect();
+                match slice_indices(chars.len(), start, end, step) {
+                    Some(indices) => {
+                        let string = indices.map(|i| chars[i]).collect();
+                        self.push(Value::String(Arc::new(string)));
+                    }
+                    None => self.error = Some(Error::InvalidOperands),
+                }
+            }
+            _ => self.error = Some(Error::InvalidOperands),
+        }
+    }
     fn i_set(&mut self) {
         let ele = self.pop();
         let idx = self.pop();
         let val = self.pop();
         match (val, idx) {
-            (Value::Array(v), Value::Number(i)) => {
-                if v.set(i as usize, ele) {
-                    self.push(Value::Array(v));
-                } else {
+            (Value::Array(v), Value::Int(i)) => {
+                if !usize::try_from(i).is_ok_and(|i| v.set(i, ele)) {
                     self.error = Some(Error::IndexOutOfBound)
                 }
             }
+            (Value::Map(m), key) => {
+                if !m.set(key, ele) {
+                    self.error = Some(Error::InvalidOperands)
+                }
+            }
             _ => self.error = Some(Error::InvalidOperands),
         }
     }
-    fn sp(&self) -> usize {
-        self.stack.len()
+    fn i_getfield(&mut self, field: usize) {
+        match self.pop() {
+            Value::Instance(instance) => match instance.slot(field) {
+                Some(slot) => {
+                    let value = instance.slots.borrow()[slot].clone();
+                    self.push(value)
+                }
+                None => self.error = Some(Error::NoSuchField),
+            },
+            _ => self.error = Some(Error::InvalidOperands),
+        }
     }
-    pub fn error(&self) -> Option<Error> {
-        self.error.clone()
+    fn i_setfield(&mut self, field: usize) {
+        let value = self.pop();
+        match self.pop() {
+            Value::Instance(instance) => match instance.slot(field) {
+                Some(slot) => instance.slots.borrow_mut()[slot] = value,
+                None => self.error = Some(Error::NoSuchField),
+            },
+            _ => self.error = Some(Error::InvalidOperands),
+        }
     }
-    pub fn push_args(&mut self, argc: usize, param_count: usize) {
-        if argc >= param_count as usize {
-            for _ in 0..(argc - param_count as usize) {
-                self.pop();
+    fn i_jmp(&mut self, address: usize) {
+        *self.ip() = address;
+    }
+    fn i_cjmp(&mut self, address: usize) {
+        if !self.pop().truthy() {
+            *self.ip() = address;
+        }
+    }
+    fn i_and(&mut self, address: usize) {
+        if self.stack.last().unwrap().truthy() {
+            self.pop();
+        } else {
+            *self.ip() = address;
+        }
+    }
+    fn i_or(&mut self, address: usize) {
+        if self.stack.last().unwrap().truthy() {
+            *self.ip() = address;
+        } else {
+            self.pop();
+        }
+    }
+    fn i_not(&mut self) {
+        let value = self.pop();
+        self.push(Value::Boolean(!value.truthy()));
+    }
+    fn i_next(&mut self, address: usize) {
+        let sp = self.sp();
+        if let Value::Coroutine(coroutine) = &self.stack[sp - 2] {
+            let coroutine = coroutine.clone();
+            match self.resume(&coroutine, Value::Nil) {
+                Some(ele) => self.push(ele),
+                None => *self.ip() = address,
             }
+            return;
+        }
+        let index = match self.stack[sp - 1] {
+            Value::Int(i) => i as usize,
+            _ => {
+                self.error = Some(Error::InvalidOperands);
+                return;
+            }
+        };
+        let mut step = 1;
+        let next = match &self.stack[sp - 2] {
+            Value::Array(v) => v.get(index),
+            Value::String(v) => v.get(index..).and_then(|s| s.chars().next()).map(|c| {
+                step = c.len_utf8();
+                Value::String(Arc::new(c.to_string()))
+            }),
+            _ => {
+                self.error = Some(Error::InvalidOperands);
+                return;
+            }
+        };
+        match next {
+            Some(ele) => {
+                self.stack[sp - 1] = Value::Int((index + step) as i64);
+                self.push(ele);
+            }
+            None => *self.ip() = address,
+        }
+    }
+    fn capture_upvalue(&mut self, address: usize) -> Rc<RefCell<Upvalue>> {
+        for upvalue in self.upvalues.iter() {
+            if let Upvalue::Open(_, a) = *upvalue.borrow() {
+                if a == address {
+                    return upvalue.clone();
+                }
+            }
+        }
+        let owner = self.coroutine.clone();
+        let upvalue = Rc::new(RefCell::new(Upvalue::Open(owner, address)));
+        self.upvalues.push(upvalue.clone());
+        upvalue
+    }
+    fn close_upvalues(&mut self, from: usize) {
+        let stack = &self.stack;
+        self.upvalues.retain(|upvalue| {
+            let mut upvalue = upvalue.borrow_mut();
+            match *upvalue {
+                Upvalue::Open(_, address) if address >= from => {
+                    *upvalue = Upvalue::Closed(stack[address].clone());
+                    false
+                }
+                _ => true,
+            }
+        });
+    }
+    fn upvalue(&mut self, index: usize) -> Rc<RefCell<Upvalue>> {
+        let closure = self.frames.last().unwrap().closure.as_ref().unwrap();
+        closure.upvalues[index].clone()
+    }
+    fn i_capture(&mut self, operand: usize) {
+        let upvalue = if operand & 1 == 1 {
+            let address = self.bp() + (operand >> 1);
+            self.capture_upvalue(address)
         } else {
-            for _ in 0..(param_count as usize - argc) {
-                self.push(Value::Nil);
+            self.upvalue(operand >> 1)
+        };
+        let closure = match self.pop() {
+            Value::Function(function) => Closure {
+                function,
+                upvalues: vec![upvalue],
+            },
+            Value::Closure(closure) => {
+                let mut upvalues = closure.upvalues.clone();
+                upvalues.push(upvalue);
+                Closure {
+                    function: closure.function,
+                    upvalues,
+                }
             }
+            _ => panic!("IMPOSSIBLE!"),
+        };
+        self.push(Value::Closure(Rc::new(closure)));
+    }
+    fn open_slot<R>(
+        &mut self,
+        owner: &Option<Weak<RefCell<Coroutine>>>,
+        address: usize,
+        access: impl FnOnce(&mut Value) -> R,
+    ) -> R {
+        let same = |other: &Option<Weak<RefCell<Coroutine>>>| match (owner, other) {
+            (Some(a), Some(b)) => a.ptr_eq(b),
+            (None, None) => true,
+            _ => false,
+        };
+        if same(&self.coroutine) {
+            return access(&mut self.stack[address]);
+        }
+        if let Some(caller) = self.suspended.iter_mut().find(|s| same(&s.owner)) {
+            return access(&mut caller.stack[address]);
         }
+        match owner.as_ref().and_then(Weak::upgrade) {
+            Some(coroutine) => access(&mut coroutine.borrow_mut().stack[address]),
+            None => panic!("IMPOSSIBLE!"),
+        }
+    }
+    fn i_loadup(&mut self, index: usize) {
+        let upvalue = self.upvalue(index);
+        let value = match &*upvalue.borrow() {
+            Upvalue::Open(owner, address) => self.open_slot(owner, *address, |v| v.clone()),
+            Upvalue::Closed(value) => value.clone(),
+        };
+        self.push(value);
+    }
+    fn i_storeup(&mut self, index: usize) {
+        let value = self.pop();
+        let upvalue = self.upvalue(index);
+        let mut upvalue = upvalue.borrow_mut();
+        match &mut *upvalue {
+            Upvalue::Open(owner, address) => {
+                self.open_slot(owner, *address, |v| *v = value);
+            }
+            Upvalue::Closed(closed) => *closed = value,
+        }
+    }
+    fn sp(&self) -> usize {
+        self.stack.len()
+    }
+    pub fn error(&self) -> Option<Error> {
+        self.error.clone()
+    }
+    pub fn set_error(&mut self, error: Error) {
+        self.error = Some(error);
     }
     fn i_call(&mut self, argc: usize) {
         let func = self.stack.remove(self.sp() - 1 - argc);
+        let (func, argc, receiver) = match func {
+            Value::Bound(bound) => {
+                let at = self.sp() - argc;
+                self.stack.insert(at, bound.receiver.clone());
+                (bound.method.clone(), argc + 1, 1)
+            }
+            func => (func, argc, 0),
+        };
+        let (func, closure) = match func {
+            Value::Closure(closure) => (Value::Function(closure.function), Some(closure)),
+            func => (func, None),
+        };
         match func {
             Value::Function(f) => match f {
                 Function::Bakht {
                     param_count,
+                    required,
+                    rest,
+                    generator,
                     address,
                 } => {
-                    self.push_args(argc, param_count);
-                    self.frames.push(Frame {
-                        ip: address,
-                        bp: self.sp() - argc,
-                    });
-                    self.process();
+                    if argc < required || (argc > param_count && !rest) {
+                        self.error = Some(Error::Arity(argc - receiver));
+                        return;
+                    }
+                    let bp = self.sp() - argc;
+                    if rest && argc >= param_count {
+                        let extras = self.stack.split_off(bp + param_count);
+                        self.push(Value::Array(Rc::new(Array::new(extras))));
+                    }
+                    let entry = argc.min(param_count) - required;
+                    let frame = Frame {
+                        ip: address + entry * encode(Instruction::Jmp(0)).len as usize,
+                        bp,
+                        closure,
+                        handlers: vec![],
+                    };
+                    if generator {
+                        let stack = self.stack.split_off(bp);
+                        let coroutine = Coroutine {
+                            stack,
+                            frames: vec![Frame { bp: 0, ..frame }],
+                            upvalues: vec![],
+                            started: false,
+                            running: false,
+                        };
+                        self.push(Value::Coroutine(Rc::new(RefCell::new(coroutine))));
+                    } else {
+                        self.frames.push(frame);
+                        self.process();
+                    }
                 }
                 Function::Native { param_count, func } => {
-                    self.push_args(argc, param_count);
+                    if argc != param_count {
+                        self.error = Some(Error::Arity(argc - receiver));
+                        return;
+                    }
                     let vm = std::mem::take(self);
-                    let mut bs = BakhtScript { vm };
+                    let mut bs = BakhtScript {
+                        vm,
+                        ..Default::default()
+                    };
                     func(&mut bs);
                     *self = bs.vm;
                 }
             },
+            Value::Struct(layout) => {
+                if argc != layout.fields.len() {
+                    self.error = Some(Error::Arity(argc));
+                    return;
+                }
+                let slots = self.stack.split_off(self.sp() - layout.fields.len());
+                self.push(Value::Instance(Rc::new(Instance {
+                    layout,
+                    slots: RefCell::new(slots),
+                })));
+            }
             _ => self.error = Some(Error::CallingNonFunction),
         }
     }
@@ -484,10 +1445,60 @@ impl Default for BVM {
             bin: Default::default(),
             constants: Default::default(),
             frames: Default::default(),
+            upvalues: Default::default(),
+            coroutine: None,
+            suspended: Default::default(),
+            globals: Default::default(),
+            initializers: Default::default(),
             entry: Default::default(),
+            error_struct: Default::default(),
             error: None,
+            exception: None,
+            yielded: None,
+            names: Default::default(),
+            methods: Default::default(),
+            offsets: None,
         };
         bvm.init();
         bvm
     }
 }
+
+fn slice_indices(
+    len: usize,
+    start: Value,
+    end: Value,
+    step: Value,
+) -> Option<impl Iterator<Item = usize>> {
+    let bound = |value: Value| match value {
+        Value::Nil => Some(None),
+        Value::Int(n) => Some(Some(n)),
+        _ => None,
+    };
+    let (start, end) = (bound(start)?, bound(end)?);
+    let step = bound(step)?.unwrap_or(1);
+    if step == 0 {
+        return None;
+    }
+    let len = len as i64;
+    let (lower, upper) = if step > 0 { (0, len) } else { (-1, len - 1) };
+    let clamp = |index: i64| {
+        if index < 0 {
+            (index + len).max(lower)
+        } else {
+            index.min(upper)
+        }
+    };
+    let start = start.map_or(if step > 0 { lower } else { upper }, clamp);
+    let end = end.map_or(if step > 0 { upper } else { lower }, clamp);
+    let mut index = start;
+    Some(std::iter::from_fn(move || {
+        if (step > 0 && index < end) || (step < 0 && index > end) {
+            let current = index;
+            index += step;
+            Some(current as usize)
+        } else {
+            None
+        }
+    }))
+}